@@ -2,222 +2,329 @@
 
 const SAMPLES: usize = 1;
 const BOUNCES: usize = 16;
-
-const CAMERA_ORIGIN: Vec3 = vec3(0.0, 0.0, 0.0);
-const FOCAL_LENGTH: f32 = 1.0;
-const VIEWPORT_HEIGHT: f32 = 2.0;
+const ROULETTE_START: usize = 3;
+const DIRECTION_EPSILON: f32 = 1e-8;
+/// Distance, in voxels, that scattered rays start away from the surface.
+const SURFACE_OFFSET: f32 = 1e-3;
 
 const EMPTY_MATERIAL: Material = Material {
     albedo: [0.0, 0.0, 0.0],
     roughness: 0.0,
+    emission: 0.0,
 };
 
+pub mod rng;
+
 #[allow(unused)]
 use spirv_std::num_traits::Float;
 
-use shared::{Material, PackedNode, ShaderConstants, Voxel, TREE_DEPTH};
+use rng::Rng;
+use shared::{Material, PackedNode, ShaderConstants, Voxel};
 use spirv_std::{
-    glam::{ivec3, uvec3, vec2, vec3, vec4, BVec3, IVec3, UVec3, Vec3, Vec3Swizzles, Vec4},
-    spirv,
+    glam::{ivec3, vec2, vec3, vec4, IVec3, Vec2, Vec3, Vec4, Vec4Swizzles},
+    spirv, Image,
 };
 
-struct HitResult {
-    exists: bool,
-    position: Vec3,
-    normal: Vec3,
-    material: Material,
-}
-
-struct Ray {
-    origin: Vec3,
-    direction: Vec3,
-    t: f32,
+pub struct HitResult {
+    pub exists: bool,
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub material: Material,
 }
 
-fn sphere(point: Vec3) -> f32 {
-    (point - vec3(0.0, 0.0, -30.0)).length() - 15.0
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3,
+    pub t: f32,
 }
 
-fn less_than_equal(vec1: Vec3, vec2: Vec3) -> BVec3 {
-    BVec3 {
-        x: vec1.x <= vec2.x,
-        y: vec1.y <= vec2.y,
-        z: vec1.z <= vec2.z,
-    }
-}
-
-struct GetResult {
-    exists: bool,
-    material: Material,
-}
-
-const EMPTY_GET_RESULT: GetResult = GetResult {
+const MISS: HitResult = HitResult {
     exists: false,
+    position: Vec3::ZERO,
+    normal: Vec3::ZERO,
     material: EMPTY_MATERIAL,
 };
 
-fn get(
-    x: i32,
-    y: i32,
-    z: i32,
-    nodes: &[[PackedNode; 8]],
-    voxels: &[Voxel],
-    root: PackedNode,
-) -> GetResult {
-    if x < 0 || x >= 2_i32.pow(TREE_DEPTH) {
-        return EMPTY_GET_RESULT;
-    }
-    if y < 0 || y >= 2_i32.pow(TREE_DEPTH) {
-        return EMPTY_GET_RESULT;
-    }
-    if z < 0 || z >= 2_i32.pow(TREE_DEPTH) {
-        return EMPTY_GET_RESULT;
-    }
+/// Reciprocal of the ray direction with zero components nudged away from zero, so
+/// the slab distances below never turn into `0 * inf`.
+pub fn safe_inverse(direction: Vec3) -> Vec3 {
+    let nudge = |d: f32| {
+        if d.abs() >= DIRECTION_EPSILON {
+            d
+        } else if d < 0.0 {
+            -DIRECTION_EPSILON
+        } else {
+            DIRECTION_EPSILON
+        }
+    };
 
-    let mut x = x as u32;
-    let mut y = y as u32;
-    let mut z = z as u32;
+    1.0 / vec3(nudge(direction.x), nudge(direction.y), nudge(direction.z))
+}
 
-    let mut node = root;
-    let mut s = 2_u32.pow(TREE_DEPTH - 1);
+impl Ray {
+    /// Walks the chunk grid along the ray. The grid covers
+    /// `constants.world_size` units from `constants.world_origin`, split into
+    /// `chunk_grid` chunks of `2^tree_depth` cells per axis, and `chunks` holds
+    /// the root of each chunk's octree in x, y, z order.
+    ///
+    /// Every step looks up the chunk containing the current cell, descends from
+    /// its root to the leaf containing the cell and, if that leaf is empty, jumps
+    /// straight to the face the ray leaves it through, so empty chunks, empty
+    /// subtrees and large uniform leaves cost a single step. Each step crosses at
+    /// least one cell boundary and never turns back along an axis, so the walk
+    /// leaves the grid within `3 * tree_size` steps however finely it is split.
+    ///
+    /// Descent stops early at branches that cover less than
+    /// `constants.lod_bias` pixels at the current distance, which are then drawn
+    /// as their entry in `lods`: solid with a representative voxel, or empty.
+    pub fn traverse(
+        &mut self,
+        constants: &ShaderConstants,
+        chunks: &[PackedNode],
+        nodes: &[[PackedNode; 8]],
+        lods: &[PackedNode],
+        voxels: &[Voxel],
+        materials: &[Material],
+    ) -> HitResult {
+        let chunk_size = 1_i32 << constants.tree_depth;
+        let grid = constants.chunk_grid as i32;
+        let tree_size = grid * chunk_size;
+
+        // Scaling origin and direction by the same factor keeps `t` identical in
+        // world and tree space.
+        let scale = tree_size as f32 / constants.world_size;
+        let origin = (self.origin - Vec3::from(constants.world_origin)) * scale;
+        let direction = self.direction * scale;
+
+        // Cells per unit of distance below which a branch is too small to descend.
+        let lod_scale = if constants.lod_bias > 0.0 {
+            let pixel_angle = 2.0 * (constants.camera_fov * 0.5).tan() / constants.height as f32;
+            constants.lod_bias * pixel_angle * scale
+        } else {
+            0.0
+        };
 
-    loop {
-        if node.is_empty() {
-            return EMPTY_GET_RESULT;
+        let inv_direction = safe_inverse(direction);
+        let step = ivec3(
+            if inv_direction.x > 0.0 { 1 } else { -1 },
+            if inv_direction.y > 0.0 { 1 } else { -1 },
+            if inv_direction.z > 0.0 { 1 } else { -1 },
+        );
+
+        let t0 = -origin * inv_direction;
+        let t1 = (Vec3::splat(tree_size as f32) - origin) * inv_direction;
+        let t_near = t0.min(t1);
+        let t_enter = t_near.max_element();
+        let t_exit = t0.max(t1).min_element();
+
+        if t_exit < t_enter.max(0.0) {
+            return MISS;
         }
-        if node.is_leaf() {
-            return GetResult {
-                exists: true,
-                material: voxels[(node.0 & !(1 << 31)) as usize].material,
+
+        let mut t = t_enter.max(0.0);
+        let mut normal = Vec3::ZERO;
+        if t_enter > 0.0 {
+            normal = if t_near.x >= t_near.y && t_near.x >= t_near.z {
+                vec3(-step.x as f32, 0.0, 0.0)
+            } else if t_near.y >= t_near.z {
+                vec3(0.0, -step.y as f32, 0.0)
+            } else {
+                vec3(0.0, 0.0, -step.z as f32)
             };
         }
 
-        // is branch
-        let index = ((x >= s) as usize) << 0 | ((y >= s) as usize) << 1 | ((z >= s) as usize) << 2;
+        let mut cell = (origin + direction * t)
+            .floor()
+            .clamp(Vec3::ZERO, Vec3::splat((tree_size - 1) as f32))
+            .as_ivec3();
+
+        for _ in 0..3 * tree_size {
+            let chunk = cell / chunk_size;
+            let mut node = chunks[(chunk.x + (chunk.y + chunk.z * grid) * grid) as usize];
+            let mut node_min = chunk * chunk_size;
+            let mut node_size = chunk_size;
+
+            while !node.is_leaf() {
+                if (node_size as f32) < lod_scale * t {
+                    node = lods[node.0 as usize];
+                    break;
+                }
+
+                node_size /= 2;
+                let upper = cell.cmpge(node_min + node_size);
+                let index = upper.x as usize | (upper.y as usize) << 1 | (upper.z as usize) << 2;
+
+                node_min += IVec3::select(upper, IVec3::splat(node_size), IVec3::ZERO);
+                node = nodes[node.0 as usize][index];
+            }
 
-        node = nodes[node.0 as usize][index];
+            if !node.is_empty() {
+                self.t = t;
+                return HitResult {
+                    exists: true,
+                    position: self.origin + self.direction * t,
+                    normal,
+                    material: materials[voxels[(node.0 & !(1 << 31)) as usize].material as usize],
+                };
+            }
 
-        x %= s;
-        y %= s;
-        z %= s;
+            let exit_planes = node_min
+                + IVec3::select(
+                    step.cmpgt(IVec3::ZERO),
+                    IVec3::splat(node_size),
+                    IVec3::ZERO,
+                );
+            let t_planes = (exit_planes.as_vec3() - origin) * inv_direction;
+
+            let node_max = node_min + node_size - 1;
+            let next = (origin + direction * t_planes.min_element())
+                .floor()
+                .clamp(node_min.as_vec3(), node_max.as_vec3())
+                .as_ivec3();
+
+            if t_planes.x <= t_planes.y && t_planes.x <= t_planes.z {
+                t = t_planes.x;
+                cell = ivec3(
+                    if step.x > 0 {
+                        node_max.x + 1
+                    } else {
+                        node_min.x - 1
+                    },
+                    next.y,
+                    next.z,
+                );
+                normal = vec3(-step.x as f32, 0.0, 0.0);
+            } else if t_planes.y <= t_planes.z {
+                t = t_planes.y;
+                cell = ivec3(
+                    next.x,
+                    if step.y > 0 {
+                        node_max.y + 1
+                    } else {
+                        node_min.y - 1
+                    },
+                    next.z,
+                );
+                normal = vec3(0.0, -step.y as f32, 0.0);
+            } else {
+                t = t_planes.z;
+                cell = ivec3(
+                    next.x,
+                    next.y,
+                    if step.z > 0 {
+                        node_max.z + 1
+                    } else {
+                        node_min.z - 1
+                    },
+                );
+                normal = vec3(0.0, 0.0, -step.z as f32);
+            }
 
-        s /= 2;
-    }
+            if cell.cmplt(IVec3::ZERO).any() || cell.cmpge(IVec3::splat(tree_size)).any() {
+                break;
+            }
+        }
 
-    // let mut node = PackedNode(0); // root node
-    //
-    // let mut s = 2_u32.pow(TREE_DEPTH);
-    //
-    // loop {
-    //     if node.is_empty() {
-    //         return EMPTY_GET_RESULT;
-    //     }
-    //
-    //     if node.is_leaf() {
-    //         return GetResult {
-    //             exists: true,
-    //             material: voxels[(node.0 - (1 << 31)) as usize].material,
-    //         };
-    //     }
-    //
-    //     let index = ((x >= s as i32) as usize) << 0
-    //         | ((y >= s as i32) as usize) << 1
-    //         | ((z >= s as i32) as usize) << 2;
-    //
-    //     node = nodes[node.0 as usize][index];
-    //
-    //     x %= s as i32;
-    //     y %= s as i32;
-    //     z %= s as i32;
-    //
-    //     s /= 2;
-    // }
-}
+        MISS
+    }
 
-impl Ray {
-    fn traverse(
+    /// Path traces the ray through the scene, returning the radiance it carries
+    /// back. Every hit scatters the ray between a mirror reflection and a diffuse
+    /// bounce according to the material's roughness, and paths end when they
+    /// reach the sky, run out of bounces or lose the Russian roulette.
+    #[allow(clippy::too_many_arguments)]
+    pub fn color(
         &mut self,
+        constants: &ShaderConstants,
+        chunks: &[PackedNode],
         nodes: &[[PackedNode; 8]],
+        lods: &[PackedNode],
         voxels: &[Voxel],
-        root: PackedNode,
-    ) -> HitResult {
-        let delta_dist = 1.0 / self.direction.abs();
-        let ray_step = self.direction.signum().as_ivec3();
-        let mut map_pos = self.origin.floor().as_ivec3();
-        let mut side_dist = (self.direction.signum() * (map_pos.as_vec3() - self.origin)
-            + (self.direction * 0.5)
-            + 0.5)
-            * delta_dist;
-
-        let mut mask = BVec3 {
-            x: false,
-            y: false,
-            z: false,
-        };
+        materials: &[Material],
+        rng: &mut Rng,
+    ) -> Vec3 {
+        let voxel_size = constants.voxel_size();
 
-        let mut fmask = vec3(0.0, 0.0, 0.0);
-
-        for _ in 0..30 {
-            // self.t += sphere(self.origin);
-            self.origin += self.direction;
-
-            // if sphere(map_pos.as_vec3()) < 0.01 {
-            // let uposition = uvec3(map_pos.x as u32, map_pos.y as u32, map_pos.z as u32);
-            // let uposition = map_pos.as_uvec3();
-            let get_result = get(
-                map_pos.x - 3,
-                map_pos.y + 3,
-                map_pos.z + 15,
-                nodes,
-                voxels,
-                root,
-            );
-            if get_result.exists {
-                let d = (fmask * (side_dist - delta_dist)).length() / 100.0;
+        let mut radiance = Vec3::ZERO;
+        let mut throughput = Vec3::ONE;
 
-                return HitResult {
-                    exists: true,
-                    position: Vec3::ZERO,
-                    normal: Vec3::ZERO,
-                    material: get_result.material,
-                };
+        for bounce in 0..BOUNCES {
+            let hit_result = self.traverse(constants, chunks, nodes, lods, voxels, materials);
+
+            if !hit_result.exists {
+                radiance += throughput * sky(self.direction);
+                break;
             }
 
-            mask = less_than_equal(side_dist.xyz(), side_dist.yzx().min(side_dist.zxy()));
-            fmask = vec3(
-                mask.x as i32 as f32,
-                mask.y as i32 as f32,
-                mask.z as i32 as f32,
-            );
-            side_dist += fmask * delta_dist;
-            map_pos += fmask.as_ivec3() * ray_step;
-        }
+            let material = hit_result.material;
+            let albedo = Vec3::from(material.albedo);
+            radiance += throughput * albedo * material.emission;
+            throughput *= albedo;
+
+            // Rays starting inside a voxel have no face to scatter from.
+            if hit_result.normal == Vec3::ZERO {
+                break;
+            }
 
-        // let d = (fmask * (side_dist - delta_dist)).length() / 100.0;
+            let diffuse = rng::cosine_hemisphere(hit_result.normal, rng.next_vec2());
+            let mirror =
+                self.direction - 2.0 * self.direction.dot(hit_result.normal) * hit_result.normal;
+            let mut direction = mirror.lerp(diffuse, material.roughness).normalize_or_zero();
+            if direction.dot(hit_result.normal) <= 0.0 {
+                direction = diffuse;
+            }
+            if direction == Vec3::ZERO {
+                break;
+            }
 
-        HitResult {
-            exists: false,
-            position: Vec3::ZERO,
-            normal: Vec3::ZERO,
-            material: Material {
-                albedo: [0.0, 0.0, 0.0],
-                roughness: 0.0,
-            },
+            // Start just outside the face that was hit so the next traversal does
+            // not immediately find the same voxel again.
+            self.origin = hit_result.position + hit_result.normal * (voxel_size * SURFACE_OFFSET);
+            self.direction = direction;
+
+            if bounce >= ROULETTE_START {
+                let survival = throughput.max_element();
+                if rng.next_f32() >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
         }
+
+        radiance
     }
+}
 
-    fn color(&mut self, nodes: &[[PackedNode; 8]], voxels: &[Voxel], root: PackedNode) -> Vec3 {
-        let hit_result = self.traverse(nodes, voxels, root);
+/// Light arriving from outside the world along `direction`.
+pub fn sky(direction: Vec3) -> Vec3 {
+    let t = 0.5 * (direction.y + 1.0);
 
-        if hit_result.exists {
-            vec3(
-                hit_result.material.albedo[0],
-                hit_result.material.albedo[1],
-                hit_result.material.albedo[2],
-            )
-        } else {
-            Vec3::ZERO
-        }
+    Vec3::ONE.lerp(vec3(0.5, 0.7, 1.0), t)
+}
+
+/// Primary ray through `pixel`, given in framebuffer coordinates with y pointing
+/// down. Yaw turns the camera around +Y starting from -Z, pitch tilts it up, and
+/// `camera_fov` is the vertical field of view in radians.
+pub fn camera_ray(constants: &ShaderConstants, pixel: Vec2) -> Ray {
+    let (sin_yaw, cos_yaw) = constants.camera_yaw.sin_cos();
+    let (sin_pitch, cos_pitch) = constants.camera_pitch.sin_cos();
+
+    let forward = vec3(sin_yaw * cos_pitch, sin_pitch, -cos_yaw * cos_pitch);
+    let right = vec3(cos_yaw, 0.0, sin_yaw);
+    let up = right.cross(forward);
+
+    let half_height = (constants.camera_fov * 0.5).tan();
+    let half_width = half_height * constants.width as f32 / constants.height as f32;
+    let ndc = vec2(
+        pixel.x / constants.width as f32 * 2.0 - 1.0,
+        1.0 - pixel.y / constants.height as f32 * 2.0,
+    );
+
+    Ray {
+        origin: Vec3::from(constants.camera_position),
+        direction: (forward + right * (ndc.x * half_width) + up * (ndc.y * half_height))
+            .normalize(),
+        t: 0.0,
     }
 }
 
@@ -233,45 +340,79 @@ pub fn main_vs(
     *out_pos = vec4(uv_out.x, uv_out.y, 0.0, 1.0);
 }
 
+/// Color of the pixel whose center is at `frag_coord`. Shared by `main_fs` and the
+/// CPU reference renderer, so both trace exactly the same code.
+pub fn render_pixel(
+    frag_coord: Vec2,
+    constants: &ShaderConstants,
+    chunks: &[PackedNode],
+    nodes: &[[PackedNode; 8]],
+    lods: &[PackedNode],
+    voxels: &[Voxel],
+    materials: &[Material],
+) -> Vec4 {
+    let mut rng = Rng::new(
+        frag_coord.as_uvec2(),
+        constants.time,
+        constants.sample_count,
+    );
+    let mut color = vec3(0.0, 0.0, 0.0);
+
+    for _ in 0..SAMPLES {
+        let mut ray = camera_ray(constants, frag_coord);
+
+        color += ray.color(constants, chunks, nodes, lods, voxels, materials, &mut rng);
+    }
+
+    color /= SAMPLES as f32;
+
+    vec4(color.x, color.y, color.z, 1.0)
+}
+
+/// Folds `sample` into the running average `previous` of `sample_count` earlier
+/// samples. A count of zero discards `previous`, which is how accumulation resets.
+pub fn accumulate(previous: Vec4, sample: Vec4, sample_count: u32) -> Vec4 {
+    if sample_count == 0 {
+        sample
+    } else {
+        previous + (sample - previous) / (sample_count + 1) as f32
+    }
+}
+
 // Fragment
 #[spirv(fragment)]
+#[allow(clippy::too_many_arguments)]
 pub fn main_fs(
     #[spirv(frag_coord)] frag_coord: Vec4,
     #[spirv(push_constant)] constants: &ShaderConstants,
 
     #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] nodes: &[[PackedNode; 8]],
     #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] voxels: &[Voxel],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] materials: &[Material],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] chunks: &[PackedNode],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] lods: &[PackedNode],
+
+    #[spirv(descriptor_set = 1, binding = 0)] previous: &Image!(2D, type=f32, sampled),
+    #[spirv(descriptor_set = 1, binding = 1)] accumulation: &Image!(
+        2D,
+        format = rgba32f,
+        sampled = false
+    ),
 
     output: &mut Vec4,
 ) {
-    let aspect_ratio = constants.width as f32 / constants.height as f32;
-    let viewport_width = VIEWPORT_HEIGHT * aspect_ratio;
-
-    let viewport_u = vec3(viewport_width, 0.0, 0.0);
-    let viewport_v = vec3(0.0, -VIEWPORT_HEIGHT, 0.0);
-
-    let pixel_delta_u = viewport_u / constants.width as f32;
-    let pixel_delta_v = viewport_v / constants.height as f32;
-
-    let viewport_upper_left =
-        CAMERA_ORIGIN - vec3(0.0, 0.0, FOCAL_LENGTH) - viewport_u / 2.0 - viewport_v / 2.0;
-
-    let mut color = vec3(0.0, 0.0, 0.0);
-
-    for _ in 0..SAMPLES {
-        let pixel_center =
-            viewport_upper_left + frag_coord.x * pixel_delta_u + frag_coord.y * pixel_delta_v;
-
-        let mut ray = Ray {
-            origin: CAMERA_ORIGIN,
-            direction: (pixel_center - CAMERA_ORIGIN).normalize(),
-            t: 0.0,
-        };
-
-        color += ray.color(nodes, voxels, constants.root_node);
-    }
-
-    color /= SAMPLES as f32;
-
-    *output = vec4(color.x, color.y, color.z, 1.0);
+    let pixel = frag_coord.xy().as_ivec2();
+    let sample = render_pixel(
+        frag_coord.xy(),
+        constants,
+        chunks,
+        nodes,
+        lods,
+        voxels,
+        materials,
+    );
+    let color = accumulate(previous.fetch(pixel), sample, constants.sample_count);
+
+    unsafe { accumulation.write(pixel, color) };
+    *output = color;
 }
@@ -0,0 +1,247 @@
+//! Random numbers and sampling routines for the path tracer. Everything here is
+//! plain `no_std` arithmetic on `u32` and `f32`, so it runs unchanged in SPIR-V
+//! and in host tests.
+//!
+//! The sampling functions take their uniform inputs as a [`Vec2`] instead of an
+//! [`Rng`], which keeps them deterministic and lets callers choose where the
+//! numbers come from.
+
+use core::f32::consts::PI;
+
+#[allow(unused)]
+use spirv_std::num_traits::Float;
+
+use spirv_std::glam::{vec2, vec3, UVec2, Vec2, Vec3};
+
+/// PCG hash (RXS-M-XS output on a 32-bit LCG step). Good enough to decorrelate
+/// the streams of neighbouring pixels and frames while staying a handful of
+/// integer instructions.
+pub fn hash(x: u32) -> u32 {
+    let state = x.wrapping_mul(747_796_405).wrapping_add(2_891_336_453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277_803_737);
+
+    (word >> 22) ^ word
+}
+
+/// Per-pixel random number generator, advanced by rehashing its state.
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    /// Stream for `pixel` in frame `frame` at time `time`. Every input changes
+    /// the whole stream, so accumulated frames never repeat a sample.
+    pub fn new(pixel: UVec2, time: f32, frame: u32) -> Self {
+        Self {
+            state: hash(pixel.x ^ hash(pixel.y ^ hash(time.to_bits() ^ hash(frame)))),
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state = hash(self.state);
+        self.state
+    }
+
+    /// Uniform float in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+
+    /// Two independent uniform floats in `[0, 1)`.
+    pub fn next_vec2(&mut self) -> Vec2 {
+        vec2(self.next_f32(), self.next_f32())
+    }
+}
+
+/// Tangent and bitangent completing `normal` to a right-handed orthonormal basis
+/// (Duff et al., "Building an Orthonormal Basis, Revisited").
+fn tangent_frame(normal: Vec3) -> (Vec3, Vec3) {
+    let sign = if normal.z >= 0.0 { 1.0 } else { -1.0 };
+    let a = -1.0 / (sign + normal.z);
+    let b = normal.x * normal.y * a;
+
+    (
+        vec3(
+            1.0 + sign * normal.x * normal.x * a,
+            sign * b,
+            -sign * normal.x,
+        ),
+        vec3(b, sign + normal.y * normal.y * a, -normal.y),
+    )
+}
+
+/// Rotates `local`, expressed with +Z as the up axis, into the hemisphere around
+/// `normal`.
+fn to_world(local: Vec3, normal: Vec3) -> Vec3 {
+    let (tangent, bitangent) = tangent_frame(normal);
+
+    tangent * local.x + bitangent * local.y + normal * local.z
+}
+
+/// Direction around +Z with the given cosine to the axis and azimuth `u`.
+fn spherical(cos_theta: f32, u: f32) -> Vec3 {
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let (sin_phi, cos_phi) = (u * 2.0 * PI).sin_cos();
+
+    vec3(sin_theta * cos_phi, sin_theta * sin_phi, cos_theta)
+}
+
+/// Uniformly distributed point on the unit sphere.
+pub fn unit_vector(u: Vec2) -> Vec3 {
+    spherical(u.x * 2.0 - 1.0, u.y)
+}
+
+/// Direction uniformly distributed over the hemisphere around `normal`.
+pub fn uniform_hemisphere(normal: Vec3, u: Vec2) -> Vec3 {
+    to_world(spherical(u.x, u.y), normal)
+}
+
+/// Direction in the hemisphere around `normal` with density proportional to its
+/// cosine with `normal`, matching a Lambertian surface.
+pub fn cosine_hemisphere(normal: Vec3, u: Vec2) -> Vec3 {
+    to_world(spherical(u.x.sqrt(), u.y), normal)
+}
+
+/// Microfacet normal drawn from the GGX distribution around `normal`, weighted by
+/// its cosine to `normal`. `roughness` is the perceptual roughness; the GGX alpha
+/// is its square. Reflect the incoming direction about the result to sample a
+/// glossy bounce.
+pub fn ggx(normal: Vec3, roughness: f32, u: Vec2) -> Vec3 {
+    let alpha = roughness * roughness;
+    let cos_theta = ((1.0 - u.x) / (1.0 + (alpha * alpha - 1.0) * u.x)).sqrt();
+
+    to_world(spherical(cos_theta, u.y), normal)
+}
+
+/// Point uniformly distributed in the unit disk, using Shirley and Chiu's
+/// concentric mapping so nearby inputs stay nearby.
+pub fn disk(u: Vec2) -> Vec2 {
+    let offset = u * 2.0 - Vec2::ONE;
+    if offset == Vec2::ZERO {
+        return Vec2::ZERO;
+    }
+
+    let (radius, theta) = if offset.x.abs() > offset.y.abs() {
+        (offset.x, PI / 4.0 * (offset.y / offset.x))
+    } else {
+        (offset.y, PI / 2.0 - PI / 4.0 * (offset.x / offset.y))
+    };
+    let (sin, cos) = theta.sin_cos();
+
+    vec2(cos, sin) * radius
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: usize = 20_000;
+
+    fn normals() -> [Vec3; 5] {
+        [
+            Vec3::Y,
+            Vec3::NEG_Z,
+            Vec3::Z,
+            vec3(1.0, -2.0, 0.5).normalize(),
+            vec3(-0.3, 0.1, -0.9).normalize(),
+        ]
+    }
+
+    /// Mean of `f` over `SAMPLES` draws from a fixed stream.
+    fn mean(mut f: impl FnMut(Vec2) -> f32) -> f32 {
+        let mut rng = Rng::new(UVec2::new(3, 7), 0.0, 0);
+
+        (0..SAMPLES).map(|_| f(rng.next_vec2())).sum::<f32>() / SAMPLES as f32
+    }
+
+    #[test]
+    fn floats_are_uniform_in_the_unit_interval() {
+        let mut rng = Rng::new(UVec2::new(12, 34), 1.5, 2);
+        let values: [f32; SAMPLES] = core::array::from_fn(|_| rng.next_f32());
+
+        assert!(values.iter().all(|value| (0.0..1.0).contains(value)));
+
+        let average = values.iter().sum::<f32>() / SAMPLES as f32;
+        assert!((average - 0.5).abs() < 0.01, "{average}");
+
+        let below_tenth = values.iter().filter(|value| **value < 0.1).count();
+        assert!((below_tenth as f32 / SAMPLES as f32 - 0.1).abs() < 0.01);
+    }
+
+    #[test]
+    fn streams_differ_between_pixels_times_and_frames() {
+        let first = |pixel, time, frame| Rng::new(pixel, time, frame).next_u32();
+        let base = first(UVec2::new(5, 5), 1.0, 0);
+
+        assert_ne!(base, first(UVec2::new(6, 5), 1.0, 0));
+        assert_ne!(base, first(UVec2::new(5, 6), 1.0, 0));
+        assert_ne!(base, first(UVec2::new(5, 5), 2.0, 0));
+        assert_ne!(base, first(UVec2::new(5, 5), 1.0, 1));
+        assert_eq!(base, first(UVec2::new(5, 5), 1.0, 0));
+    }
+
+    #[test]
+    fn hemisphere_samples_are_unit_vectors_above_the_surface() {
+        let mut rng = Rng::new(UVec2::ZERO, 0.0, 0);
+
+        for normal in normals() {
+            for _ in 0..1000 {
+                for direction in [
+                    uniform_hemisphere(normal, rng.next_vec2()),
+                    cosine_hemisphere(normal, rng.next_vec2()),
+                    ggx(normal, 0.6, rng.next_vec2()),
+                ] {
+                    assert!((direction.length() - 1.0).abs() < 1e-4, "{direction}");
+                    assert!(direction.dot(normal) >= -1e-4, "{direction} {normal}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hemisphere_samples_have_the_expected_mean_cosine() {
+        for normal in normals() {
+            let uniform = mean(|u| uniform_hemisphere(normal, u).dot(normal));
+            let cosine = mean(|u| cosine_hemisphere(normal, u).dot(normal));
+
+            assert!((uniform - 1.0 / 2.0).abs() < 0.01, "{uniform}");
+            assert!((cosine - 2.0 / 3.0).abs() < 0.01, "{cosine}");
+        }
+    }
+
+    #[test]
+    fn unit_vectors_cover_the_whole_sphere() {
+        let average = (0..3)
+            .map(|axis| mean(|u| unit_vector(u)[axis]))
+            .collect::<Vec<_>>();
+
+        assert!(average.iter().all(|a| a.abs() < 0.02), "{average:?}");
+        assert!((mean(|u| unit_vector(u).length()) - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn ggx_lobe_narrows_with_roughness() {
+        let normal = vec3(0.2, 0.9, -0.4).normalize();
+        let spread = |roughness| mean(|u| 1.0 - ggx(normal, roughness, u).dot(normal));
+
+        assert!(spread(0.0) < 1e-6);
+        assert!(spread(0.1) < spread(0.5));
+        assert!(spread(0.5) < spread(1.0));
+        // With alpha = 1 the GGX lobe is exactly the cosine-weighted hemisphere.
+        assert!((1.0 - spread(1.0) - 2.0 / 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn disk_samples_are_uniform_in_the_unit_disk() {
+        let mut rng = Rng::new(UVec2::new(1, 2), 0.0, 3);
+        for _ in 0..1000 {
+            assert!(disk(rng.next_vec2()).length() <= 1.0 + 1e-5);
+        }
+
+        // Uniform density over area puts half the mass inside radius^2 = 1/2.
+        let inner = mean(|u| (disk(u).length_squared() < 0.5) as u32 as f32);
+        assert!((inner - 0.5).abs() < 0.01, "{inner}");
+        assert!(mean(|u| disk(u).x).abs() < 0.01);
+        assert!(mean(|u| disk(u).y).abs() < 0.01);
+    }
+}
@@ -12,20 +12,48 @@ pub struct ShaderConstants {
     pub width: u32,
     pub height: u32,
     pub time: f32,
-    pub root_node: PackedNode,
+    pub sample_count: u32,
+    /// Chunks per axis in the chunk table. Each entry is the root of an octree
+    /// of depth `tree_depth`; a single octree is a table of one chunk.
+    pub chunk_grid: u32,
+    pub tree_depth: u32,
+    /// Minimum corner of the chunk grid, which spans `world_size` units per axis.
+    pub world_origin: [f32; 3],
+    pub world_size: f32,
+    pub camera_position: [f32; 3],
+    pub camera_yaw: f32,
+    pub camera_pitch: f32,
+    pub camera_fov: f32,
+    /// Branches narrower on screen than this many pixels are drawn as their
+    /// level-of-detail voxel instead of being descended. Zero always descends.
+    pub lod_bias: f32,
 }
 
 #[repr(C)]
-#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+#[derive(Clone, Copy, Pod, Zeroable, PartialEq, Debug)]
 pub struct Material {
     pub albedo: [f32; 3],
     pub roughness: f32,
+    pub emission: f32,
 }
 
 #[repr(C)]
-#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+#[derive(Clone, Copy, Pod, Zeroable, PartialEq, Eq, Hash, Debug)]
 pub struct Voxel {
-    pub material: Material,
+    /// Index into the material buffer.
+    pub material: u32,
+}
+
+impl ShaderConstants {
+    /// Voxels per axis across the whole chunk grid.
+    pub fn grid_voxels(&self) -> u32 {
+        self.chunk_grid << self.tree_depth
+    }
+
+    /// Edge length of one voxel in world units.
+    pub fn voxel_size(&self) -> f32 {
+        self.world_size / self.grid_voxels() as f32
+    }
 }
 
 impl PackedNode {
@@ -37,5 +65,3 @@ impl PackedNode {
         self.0 == u32::MAX
     }
 }
-
-pub const TREE_DEPTH: u32 = 3;
@@ -1,35 +1,37 @@
-use std::{time::Instant, num::NonZeroU64};
+use std::time::Instant;
 
-use bytemuck::Contiguous;
-use shared::{ShaderConstants, TREE_DEPTH, Voxel};
-use wgpu::util::DeviceExt;
-use winit::{window::Window, event::WindowEvent};
+use winit::{event::WindowEvent, window::Window};
 
-use crate::svo::{SparseVoxelOctree, Node};
+use crate::{
+    camera::{Camera, CameraController},
+    chunk::ChunkManager,
+    editor::Editor,
+    renderer::Renderer,
+    svo::SparseVoxelOctree,
+};
 
 pub struct State {
     pub size: winit::dpi::PhysicalSize<u32>,
     pub window: Window,
 
     surface: wgpu::Surface,
-    device: wgpu::Device,
-    queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
 
     start_time: Instant,
+    last_update: Instant,
 
-    render_pipeline: wgpu::RenderPipeline,
-    shader_constants: ShaderConstants,
+    camera: Camera,
+    camera_controller: CameraController,
+    editor: Editor,
 
-    // svo: SparseVoxelOctree,
-    // node_buffer: wgpu::Buffer,
-    // voxel_buffer: wgpu::Buffer,
-
-    bind_group: wgpu::BindGroup
+    octree: SparseVoxelOctree,
+    /// Streams a chunked world around the camera in place of `octree` when set.
+    chunks: Option<ChunkManager>,
+    pub renderer: Renderer,
 }
 
 impl State {
-    pub async fn new(window: Window) -> Self {
+    pub async fn new(window: Window, mut octree: SparseVoxelOctree) -> Self {
         let size = window.inner_size();
 
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
@@ -39,50 +41,26 @@ impl State {
 
         let surface = unsafe { instance.create_surface(&window) }.unwrap();
 
-        let adapter = instance.request_adapter(
-            &wgpu::RequestAdapterOptions {
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
                 power_preference: wgpu::PowerPreference::default(),
                 compatible_surface: Some(&surface),
                 force_fallback_adapter: false,
-            },
-        ).await.unwrap();
-
-        let (device, queue) = adapter.request_device(
-            &wgpu::DeviceDescriptor {
-                features: wgpu::Features::PUSH_CONSTANTS | wgpu::Features::SPIRV_SHADER_PASSTHROUGH,
-                limits: wgpu::Limits {
-                    max_push_constant_size: 128,
-                    ..Default::default()
-                },
-                label: None,
-            },
-            None, // Trace path
-        ).await.unwrap();
-
-        let svo = SparseVoxelOctree::new(TREE_DEPTH);
-        let packed_svo = svo.pack();
-
-        // dbg!(&packed_svo.1odes);
-
-        let node_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("node_buffer"),
-            contents: bytemuck::cast_slice(&packed_svo.nodes),
-            usage: wgpu::BufferUsages::STORAGE
-        });
+            })
+            .await
+            .unwrap();
 
-        let voxel_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("voxel_buffer"),
-            contents: bytemuck::cast_slice(&packed_svo.voxels),
-            usage: wgpu::BufferUsages::STORAGE
-        });
+        let (device, queue) = Renderer::request_device(&adapter).await.unwrap();
 
         let surface_caps = surface.get_capabilities(&adapter);
         // Shader code in this tutorial assumes an sRGB surface texture. Using a different
         // one will result all the colors coming out darker. If you want to support non
         // sRGB surfaces, you'll need to account for that when drawing to the frame.
-        let surface_format = surface_caps.formats.iter()
+        let surface_format = surface_caps
+            .formats
+            .iter()
             .copied()
-            .find(|f| !f.is_srgb())            
+            .find(|f| !f.is_srgb())
             .unwrap_or(surface_caps.formats[0]);
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -95,112 +73,39 @@ impl State {
         };
         surface.configure(&device, &config);
 
-        let shader = unsafe { device.create_shader_module_spirv(&wgpu::include_spirv_raw!(env!("shader.spv"))) };
-
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer { ty: wgpu::BufferBindingType::Storage { read_only: true }, has_dynamic_offset: false, min_binding_size: NonZeroU64::from_integer(32) },
-                    count: None
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer { ty: wgpu::BufferBindingType::Storage { read_only: true }, has_dynamic_offset: false, min_binding_size: NonZeroU64::from_integer(16) },
-                    count: None
-                }
-            ],
-            label: Some("bind_group_layout")
-        });
-
-        let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-            label: Some("Render Pipeline Layout"),
-            bind_group_layouts: &[&bind_group_layout],
-            push_constant_ranges: &[wgpu::PushConstantRange {
-                stages: wgpu::ShaderStages::FRAGMENT,
-                range: 0..std::mem::size_of::<ShaderConstants>() as u32
-            }]
-        });
-
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "main_vs",
-                buffers: &[]
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "main_fs",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL
-                })]
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: None,
-                polygon_mode: wgpu::PolygonMode::Fill,
-                unclipped_depth: false,
-                conservative: false
-            },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false
-            },
-            multiview: None
-        });
+        let mut renderer = Renderer::new(
+            device,
+            queue,
+            config.format,
+            size.width,
+            size.height,
+            &octree,
+        );
+        octree.take_dirty();
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Bind Group"),
-            layout: &render_pipeline.get_bind_group_layout(0),
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: node_buffer.as_entire_binding()
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: voxel_buffer.as_entire_binding()
-            }]
-        });
-        
         let start_time = Instant::now();
 
-        let shader_constants = ShaderConstants {
-            width: size.width,
-            height: size.height,
-            time: start_time.elapsed().as_secs_f32(),
-            root_node: packed_svo.root
-        };
-
+        let camera = Camera::overview(renderer.shader_constants.world_size);
+        let camera_controller = CameraController::new(8.0, 0.004);
+        camera.write_constants(&mut renderer.shader_constants);
 
         Self {
             size,
             window,
 
             surface,
-            device,
-            queue,
             config,
 
             start_time,
+            last_update: start_time,
 
-            render_pipeline,
-            shader_constants,
+            camera,
+            camera_controller,
+            editor: Editor::new(),
 
-            // svo,
-            // node_buffer,
-            // voxel_buffer,
-
-            bind_group
+            octree,
+            chunks: None,
+            renderer,
         }
     }
 
@@ -209,54 +114,69 @@ impl State {
             self.size = new_size;
             self.config.width = new_size.width;
             self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
+            self.surface.configure(&self.renderer.device, &self.config);
+            self.renderer.resize(new_size.width, new_size.height);
 
             self.window.request_redraw();
         }
     }
 
-    pub fn input(&mut self, event: &WindowEvent) -> bool { false }
+    pub fn octree(&self) -> &SparseVoxelOctree {
+        &self.octree
+    }
 
-    pub fn update(&mut self) {}
+    /// The scene being rendered. Edits made through it are uploaded on the next
+    /// [`State::update`].
+    pub fn octree_mut(&mut self) -> &mut SparseVoxelOctree {
+        &mut self.octree
+    }
 
-    pub async fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        self.shader_constants.width = self.size.width;
-        self.shader_constants.height = self.size.height;
-        self.shader_constants.time = self.start_time.elapsed().as_secs_f32();
+    /// Renders the chunks `manager` streams in around the camera instead of the
+    /// octree. Editing only applies to the octree, so it has no visible effect.
+    pub fn stream_chunks(&mut self, manager: ChunkManager) {
+        self.renderer.set_chunks(&manager.table());
+        self.chunks = Some(manager);
+    }
 
-        let output = self.surface.get_current_texture()?;
-        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+    pub fn input(&mut self, event: &WindowEvent) -> bool {
+        // Both see every event, since the editor tracks the cursor the camera
+        // controller consumes while looking around.
+        let edited = self.editor.process_event(event);
+        let moved = self.camera_controller.process_event(event);
+        edited || moved
+    }
 
-        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: Some("Render Encoder")
-        });
+    pub fn update(&mut self) {
+        let now = Instant::now();
+        let dt = now - self.last_update;
+        self.last_update = now;
 
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.01,
-                            g: 0.01,
-                            b: 0.01,
-                            a: 1.0
-                        }),
-                        store: true
-                    }
-                })],
-                depth_stencil_attachment: None
-            });
-
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_push_constants(wgpu::ShaderStages::FRAGMENT, 0, bytemuck::bytes_of(&self.shader_constants));
-            render_pass.set_bind_group(0, &self.bind_group, &[]);
-            render_pass.draw(0..3, 0..1);
+        if self.camera_controller.update_camera(&mut self.camera, dt) {
+            self.camera
+                .write_constants(&mut self.renderer.shader_constants);
+            self.renderer.reset_accumulation();
         }
 
-        self.queue.submit(std::iter::once(encoder.finish()));
+        if let Some(chunks) = &mut self.chunks {
+            if chunks.update(self.camera.position) {
+                self.renderer.set_chunks(&chunks.table());
+            }
+        }
+
+        self.editor
+            .update(&mut self.octree, &self.renderer.shader_constants);
+        self.renderer.update_octree(&mut self.octree);
+    }
+
+    pub async fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+        self.renderer.shader_constants.time = self.start_time.elapsed().as_secs_f32();
+
+        let output = self.surface.get_current_texture()?;
+        let view = output
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+
+        self.renderer.render(&view);
         output.present();
 
         Ok(())
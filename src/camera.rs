@@ -0,0 +1,297 @@
+use std::{f32::consts::FRAC_PI_2, time::Duration};
+
+use glam::{vec3, Vec3};
+use shared::ShaderConstants;
+use winit::{
+    dpi::PhysicalPosition,
+    event::{ElementState, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent},
+};
+
+const MAX_PITCH: f32 = FRAC_PI_2 - 0.01;
+
+/// Free-fly camera. Yaw turns around +Y starting from -Z and pitch tilts upwards,
+/// matching `shader::camera_ray`.
+pub struct Camera {
+    pub position: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+    /// Vertical field of view in radians.
+    pub fov: f32,
+}
+
+impl Camera {
+    pub fn new(position: Vec3, yaw: f32, pitch: f32, fov: f32) -> Self {
+        Self {
+            position,
+            yaw,
+            pitch,
+            fov,
+        }
+    }
+
+    /// Looks down -Z at a world of the given size from in front of its center.
+    pub fn overview(world_size: f32) -> Self {
+        Self::new(vec3(0.5, 0.5, 1.5) * world_size, 0.0, 0.0, FRAC_PI_2)
+    }
+
+    pub fn forward(&self) -> Vec3 {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+
+        vec3(sin_yaw * cos_pitch, sin_pitch, -cos_yaw * cos_pitch)
+    }
+
+    pub fn right(&self) -> Vec3 {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+
+        vec3(cos_yaw, 0.0, sin_yaw)
+    }
+
+    pub fn write_constants(&self, constants: &mut ShaderConstants) {
+        constants.camera_position = self.position.to_array();
+        constants.camera_yaw = self.yaw;
+        constants.camera_pitch = self.pitch;
+        constants.camera_fov = self.fov;
+    }
+}
+
+/// Turns window events into camera motion: WASD to move, space and left shift to
+/// rise and sink, and dragging with the right mouse button held to look around.
+pub struct CameraController {
+    pub speed: f32,
+    pub sensitivity: f32,
+
+    forward: bool,
+    backward: bool,
+    left: bool,
+    right: bool,
+    up: bool,
+    down: bool,
+
+    looking: bool,
+    last_cursor: Option<PhysicalPosition<f64>>,
+    yaw_delta: f32,
+    pitch_delta: f32,
+}
+
+impl CameraController {
+    pub fn new(speed: f32, sensitivity: f32) -> Self {
+        Self {
+            speed,
+            sensitivity,
+            forward: false,
+            backward: false,
+            left: false,
+            right: false,
+            up: false,
+            down: false,
+            looking: false,
+            last_cursor: None,
+            yaw_delta: 0.0,
+            pitch_delta: 0.0,
+        }
+    }
+
+    /// Returns whether the event was consumed by the controller.
+    pub fn process_event(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state,
+                        virtual_keycode: Some(keycode),
+                        ..
+                    },
+                ..
+            } => {
+                let pressed = *state == ElementState::Pressed;
+                match keycode {
+                    VirtualKeyCode::W => self.forward = pressed,
+                    VirtualKeyCode::S => self.backward = pressed,
+                    VirtualKeyCode::A => self.left = pressed,
+                    VirtualKeyCode::D => self.right = pressed,
+                    VirtualKeyCode::Space => self.up = pressed,
+                    VirtualKeyCode::LShift => self.down = pressed,
+                    _ => return false,
+                }
+                true
+            }
+            WindowEvent::MouseInput {
+                state,
+                button: MouseButton::Right,
+                ..
+            } => {
+                self.looking = *state == ElementState::Pressed;
+                true
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                if let (true, Some(last)) = (self.looking, self.last_cursor) {
+                    self.yaw_delta += (position.x - last.x) as f32 * self.sensitivity;
+                    self.pitch_delta -= (position.y - last.y) as f32 * self.sensitivity;
+                }
+                self.last_cursor = Some(*position);
+                self.looking
+            }
+            WindowEvent::Focused(false) => {
+                *self = Self::new(self.speed, self.sensitivity);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Applies the accumulated look deltas and moves the camera for a frame lasting
+    /// `dt`. Returns whether the camera changed.
+    pub fn update_camera(&mut self, camera: &mut Camera, dt: Duration) -> bool {
+        let mut moved = self.yaw_delta != 0.0 || self.pitch_delta != 0.0;
+
+        camera.yaw += self.yaw_delta;
+        camera.pitch = (camera.pitch + self.pitch_delta).clamp(-MAX_PITCH, MAX_PITCH);
+        self.yaw_delta = 0.0;
+        self.pitch_delta = 0.0;
+
+        let axis = |positive: bool, negative: bool| positive as i32 as f32 - negative as i32 as f32;
+        let forward = camera.forward();
+        let horizontal = vec3(forward.x, 0.0, forward.z).normalize_or_zero();
+        let direction = horizontal * axis(self.forward, self.backward)
+            + camera.right() * axis(self.right, self.left)
+            + Vec3::Y * axis(self.up, self.down);
+
+        if direction != Vec3::ZERO {
+            camera.position += direction.normalize() * self.speed * dt.as_secs_f32();
+            moved = true;
+        }
+
+        moved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{FRAC_PI_4, PI};
+
+    use glam::vec2;
+    use winit::event::DeviceId;
+
+    use super::*;
+
+    fn assert_close(actual: Vec3, expected: Vec3) {
+        assert!(actual.abs_diff_eq(expected, 1e-5), "{actual} != {expected}");
+    }
+
+    #[allow(deprecated)]
+    fn key(keycode: VirtualKeyCode, state: ElementState) -> WindowEvent<'static> {
+        WindowEvent::KeyboardInput {
+            device_id: unsafe { DeviceId::dummy() },
+            input: KeyboardInput {
+                scancode: 0,
+                state,
+                virtual_keycode: Some(keycode),
+                modifiers: Default::default(),
+            },
+            is_synthetic: false,
+        }
+    }
+
+    #[allow(deprecated)]
+    fn cursor(x: f64, y: f64) -> WindowEvent<'static> {
+        WindowEvent::CursorMoved {
+            device_id: unsafe { DeviceId::dummy() },
+            position: PhysicalPosition::new(x, y),
+            modifiers: Default::default(),
+        }
+    }
+
+    #[allow(deprecated)]
+    fn right_button(state: ElementState) -> WindowEvent<'static> {
+        WindowEvent::MouseInput {
+            device_id: unsafe { DeviceId::dummy() },
+            state,
+            button: MouseButton::Right,
+            modifiers: Default::default(),
+        }
+    }
+
+    /// Where `controller` moves a camera at the origin with the given yaw in one
+    /// second while `keys` are held.
+    fn step(yaw: f32, keys: &[VirtualKeyCode]) -> Vec3 {
+        let mut controller = CameraController::new(2.0, 0.01);
+        let mut camera = Camera::new(Vec3::ZERO, yaw, 0.5, FRAC_PI_2);
+        for keycode in keys {
+            assert!(controller.process_event(&key(*keycode, ElementState::Pressed)));
+        }
+
+        controller.update_camera(&mut camera, Duration::from_secs(1));
+        camera.position
+    }
+
+    #[test]
+    fn yaw_turns_from_negative_z_and_pitch_tilts_up() {
+        let camera = |yaw, pitch| Camera::new(Vec3::ZERO, yaw, pitch, FRAC_PI_2);
+
+        assert_close(camera(0.0, 0.0).forward(), Vec3::NEG_Z);
+        assert_close(camera(0.0, 0.0).right(), Vec3::X);
+        assert_close(camera(FRAC_PI_2, 0.0).forward(), Vec3::X);
+        assert_close(camera(FRAC_PI_2, 0.0).right(), Vec3::Z);
+        assert_close(camera(PI, 0.0).forward(), Vec3::Z);
+        assert_close(camera(0.0, FRAC_PI_2).forward(), Vec3::Y);
+        assert_close(
+            camera(0.0, FRAC_PI_4).forward(),
+            vec3(0.0, 1.0, -1.0).normalize(),
+        );
+    }
+
+    #[test]
+    fn shader_rays_follow_the_camera() {
+        let camera = Camera::new(vec3(1.0, 2.0, 3.0), 0.7, -0.3, FRAC_PI_2);
+        let mut constants: ShaderConstants = bytemuck::Zeroable::zeroed();
+        (constants.width, constants.height) = (64, 32);
+        camera.write_constants(&mut constants);
+
+        let center = shader::camera_ray(&constants, vec2(32.0, 16.0));
+        assert_close(center.origin, camera.position);
+        assert_close(center.direction, camera.forward());
+
+        // The right edge of a 90 degree tall, 2:1 frame is at atan(2) from center.
+        let edge = shader::camera_ray(&constants, vec2(64.0, 16.0));
+        assert!(edge.direction.dot(camera.right()) > 0.0);
+        assert!((edge.direction.angle_between(camera.forward()) - 2_f32.atan()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn looking_around_clamps_pitch_short_of_straight_up_and_down() {
+        let mut controller = CameraController::new(1.0, 0.01);
+        let mut camera = Camera::new(Vec3::ZERO, 0.0, 0.0, FRAC_PI_2);
+
+        // Moving the cursor without the right button held does not look around.
+        assert!(!controller.process_event(&cursor(0.0, 0.0)));
+        assert!(!controller.process_event(&cursor(50.0, 0.0)));
+        assert!(!controller.update_camera(&mut camera, Duration::ZERO));
+
+        controller.process_event(&right_button(ElementState::Pressed));
+        assert!(controller.process_event(&cursor(100.0, -1000.0)));
+        assert!(controller.update_camera(&mut camera, Duration::ZERO));
+        assert!((camera.yaw - 0.5).abs() < 1e-5);
+        assert_eq!(camera.pitch, MAX_PITCH);
+
+        controller.process_event(&cursor(100.0, 1000.0));
+        controller.update_camera(&mut camera, Duration::ZERO);
+        assert_eq!(camera.pitch, -MAX_PITCH);
+    }
+
+    #[test]
+    fn keys_move_relative_to_yaw_and_ignore_pitch() {
+        use VirtualKeyCode::*;
+
+        assert_close(step(0.0, &[W]), vec3(0.0, 0.0, -2.0));
+        assert_close(step(FRAC_PI_2, &[W]), vec3(2.0, 0.0, 0.0));
+        assert_close(step(FRAC_PI_2, &[S]), vec3(-2.0, 0.0, 0.0));
+        assert_close(step(FRAC_PI_2, &[D]), vec3(0.0, 0.0, 2.0));
+        assert_close(step(FRAC_PI_2, &[A]), vec3(0.0, 0.0, -2.0));
+        assert_close(step(0.0, &[Space]), vec3(0.0, 2.0, 0.0));
+        assert_close(step(0.0, &[LShift]), vec3(0.0, -2.0, 0.0));
+        // Diagonals move at the same speed, and opposite keys cancel out.
+        assert_close(step(0.0, &[W, D]), vec3(1.0, 0.0, -1.0) * 2_f32.sqrt());
+        assert_close(step(0.0, &[W, S, Space, LShift]), Vec3::ZERO);
+    }
+}
@@ -0,0 +1,694 @@
+//! Worlds larger than one octree, split into a grid of chunks that are each an
+//! octree of the same depth.
+//!
+//! Chunks live in a directory as scene files named after their coordinate, see
+//! [`chunk_path`]. [`ChunkManager`] keeps the chunks around the camera resident,
+//! loading them on a tokio background task and evicting the farthest ones when
+//! they outgrow a memory budget. [`ChunkTable`] lays the resident chunks out for
+//! the shader: one node, voxel and material buffer shared by every chunk, plus a
+//! dense table holding the root of each chunk in the window around the camera.
+//! Since every chunk lands in the same buffers, the budget should be the
+//! device's [`max_storage_buffer_size`](crate::gpu_octree::max_storage_buffer_size).
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use glam::{IVec3, UVec3, Vec3};
+use shared::{Material, PackedNode, ShaderConstants, Voxel};
+use tokio::sync::mpsc;
+
+use crate::{
+    gpu_octree::GrowableBuffer,
+    query::Aabb,
+    scene::{self, MappedScene, SceneError},
+    svo::{PackedSparseVoxelOctree, SparseVoxelOctree},
+};
+
+const EMPTY: PackedNode = PackedNode(u32::MAX);
+const LEAF: u32 = 1 << 31;
+
+/// File holding the chunk at `coord` within a chunk directory.
+pub fn chunk_path(dir: impl AsRef<Path>, coord: IVec3) -> PathBuf {
+    dir.as_ref()
+        .join(format!("{}_{}_{}.scene", coord.x, coord.y, coord.z))
+}
+
+/// Depth of the chunks in `dir`, read from the first chunk file found, or `None`
+/// if there are none.
+pub fn chunk_depth(dir: impl AsRef<Path>) -> Result<Option<u32>, SceneError> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) == Some("scene") {
+            return Ok(Some(MappedScene::open(path)?.depth()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Cuts `svo` into chunks of `chunk_depth` and saves the ones holding any voxels
+/// to `dir`, each with a copy of the palette. Returns how many were written.
+pub fn split(
+    svo: &SparseVoxelOctree,
+    chunk_depth: u32,
+    dir: impl AsRef<Path>,
+) -> Result<usize, SceneError> {
+    let chunk_depth = chunk_depth.min(svo.depth());
+    let chunk_size = 1 << chunk_depth;
+    let chunks = 1 << (svo.depth() - chunk_depth);
+
+    let mut written = 0;
+    for i in 0..chunks * chunks * chunks {
+        let coord = UVec3::new(i % chunks, i / chunks % chunks, i / chunks / chunks);
+        let min = coord * chunk_size;
+        if svo.is_region_empty(Aabb::new(min, min + chunk_size)) {
+            continue;
+        }
+
+        let mut chunk = SparseVoxelOctree::from_fn(chunk_depth, |x, y, z| {
+            svo.get(min.x + x, min.y + y, min.z + z).copied()
+        });
+        *chunk.palette_mut() = svo.palette().clone();
+
+        scene::save(&chunk.pack_dag(), chunk_path(&dir, coord.as_ivec3()))?;
+        written += 1;
+    }
+
+    Ok(written)
+}
+
+/// The resident chunks packed into shared buffers, with a table of chunk roots
+/// laid out as `shader::Ray::traverse` reads it. Chunks with identical palettes,
+/// such as those cut from one tree by [`split`], share a single copy.
+pub struct ChunkTable {
+    /// Coordinate of the chunk in the minimum corner of the table.
+    pub origin: IVec3,
+    /// Chunks per axis.
+    pub grid: u32,
+    /// Depth of every chunk's octree.
+    pub depth: u32,
+    pub voxel_size: f32,
+    /// Root of every chunk in x, y, z order, empty where no chunk is resident.
+    pub chunks: Vec<PackedNode>,
+    pub nodes: Vec<[PackedNode; 8]>,
+    pub lods: Vec<PackedNode>,
+    pub voxels: Vec<Voxel>,
+    pub materials: Vec<Material>,
+    /// Where each distinct palette starts and ends in `materials`.
+    palettes: Vec<Range<usize>>,
+}
+
+impl ChunkTable {
+    pub fn new(origin: IVec3, grid: u32, depth: u32, voxel_size: f32) -> Self {
+        Self {
+            origin,
+            grid,
+            depth,
+            voxel_size,
+            chunks: vec![EMPTY; (grid * grid * grid) as usize],
+            nodes: vec![],
+            lods: vec![],
+            voxels: vec![],
+            materials: vec![],
+            palettes: vec![],
+        }
+    }
+
+    /// Appends the chunk at `coord`, shifting its node, voxel and material indices
+    /// past the chunks already added. Chunks outside the table are ignored.
+    pub fn insert(&mut self, coord: IVec3, packed: &PackedSparseVoxelOctree) {
+        let Some(slot) = self.slot(coord) else {
+            return;
+        };
+        assert_eq!(
+            packed.depth, self.depth,
+            "chunk depth does not match the table"
+        );
+
+        let node_offset = self.nodes.len() as u32;
+        let voxel_offset = self.voxels.len() as u32;
+        assert!(
+            node_offset as usize + packed.nodes.len() < LEAF as usize
+                && voxel_offset as usize + packed.voxels.len() < LEAF as usize,
+            "chunk table overflows 31-bit indices"
+        );
+
+        let rebase = |node: PackedNode| {
+            if node.is_empty() {
+                node
+            } else if node.is_leaf() {
+                PackedNode(((node.0 & !LEAF) + voxel_offset) | LEAF)
+            } else {
+                PackedNode(node.0 + node_offset)
+            }
+        };
+
+        self.nodes
+            .extend(packed.nodes.iter().map(|children| children.map(rebase)));
+        self.lods.extend(packed.lods.iter().copied().map(rebase));
+        let material_offset = self.palette_offset(&packed.materials);
+        self.voxels.extend(packed.voxels.iter().map(|voxel| Voxel {
+            material: voxel.material + material_offset,
+        }));
+        self.chunks[slot] = rebase(packed.root);
+    }
+
+    /// Offset of `materials` in the table, appending them unless an earlier chunk
+    /// had the same palette.
+    fn palette_offset(&mut self, materials: &[Material]) -> u32 {
+        let existing = self
+            .palettes
+            .iter()
+            .find(|range| self.materials[(*range).clone()] == *materials);
+        if let Some(range) = existing {
+            return range.start as u32;
+        }
+
+        let start = self.materials.len();
+        self.materials.extend_from_slice(materials);
+        self.palettes.push(start..self.materials.len());
+        start as u32
+    }
+
+    fn slot(&self, coord: IVec3) -> Option<usize> {
+        let local = coord - self.origin;
+        let grid = self.grid as i32;
+        (local.cmpge(IVec3::ZERO).all() && local.cmplt(IVec3::splat(grid)).all())
+            .then_some((local.x + (local.y + local.z * grid) * grid) as usize)
+    }
+
+    /// Edge length of one chunk in world units.
+    pub fn chunk_size(&self) -> f32 {
+        self.voxel_size * (1 << self.depth) as f32
+    }
+
+    /// Points the shader at this table, placing chunk `(0, 0, 0)` at the world
+    /// origin.
+    pub fn write_constants(&self, constants: &mut ShaderConstants) {
+        constants.chunk_grid = self.grid;
+        constants.tree_depth = self.depth;
+        constants.world_origin = (self.origin.as_vec3() * self.chunk_size()).to_array();
+        constants.world_size = self.grid as f32 * self.chunk_size();
+    }
+}
+
+/// Outcome of one background load: the chunk, or `None` when it has no file.
+type Loaded = (IVec3, Result<Option<PackedSparseVoxelOctree>, SceneError>);
+
+/// Streams the chunks of a directory in and out around the camera.
+///
+/// Every chunk within `radius` chunks of the one holding the camera is loaded,
+/// nearest first, as long as the resident chunks and the loads in flight fit in
+/// the budget. A load reserves the most its file can decode to until it arrives.
+/// When a nearer chunk does not fit, the farthest are evicted, and chunks at
+/// least that far away are not requested again until the camera moves to another
+/// chunk.
+pub struct ChunkManager {
+    dir: PathBuf,
+    depth: u32,
+    voxel_size: f32,
+    radius: u32,
+    /// Bytes the resident chunks may take up on the GPU, counting the loads in
+    /// flight.
+    budget: usize,
+
+    runtime: tokio::runtime::Runtime,
+    sender: mpsc::UnboundedSender<Loaded>,
+    receiver: mpsc::UnboundedReceiver<Loaded>,
+
+    resident: HashMap<IVec3, PackedSparseVoxelOctree>,
+    resident_bytes: usize,
+    /// Chunks being loaded, with the bytes reserved for each.
+    pending: HashMap<IVec3, usize>,
+    pending_bytes: usize,
+    center: Option<IVec3>,
+    /// Squared distance from `center` at which chunks were evicted for the budget.
+    cutoff: Option<i32>,
+}
+
+impl ChunkManager {
+    /// Streams chunks of the given depth from `dir`, rendering each voxel
+    /// `voxel_size` units wide.
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        depth: u32,
+        voxel_size: f32,
+        radius: u32,
+        budget: usize,
+    ) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .thread_name("chunk-loader")
+            .enable_all()
+            .build()?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        Ok(Self {
+            dir: dir.into(),
+            depth,
+            voxel_size,
+            radius,
+            budget,
+
+            runtime,
+            sender,
+            receiver,
+
+            resident: HashMap::new(),
+            resident_bytes: 0,
+            pending: HashMap::new(),
+            pending_bytes: 0,
+            center: None,
+            cutoff: None,
+        })
+    }
+
+    /// Edge length of one chunk in world units.
+    pub fn chunk_size(&self) -> f32 {
+        self.voxel_size * (1 << self.depth) as f32
+    }
+
+    /// Chunk containing a world-space position.
+    pub fn chunk_at(&self, position: Vec3) -> IVec3 {
+        (position / self.chunk_size()).floor().as_ivec3()
+    }
+
+    pub fn is_resident(&self, coord: IVec3) -> bool {
+        self.resident.contains_key(&coord)
+    }
+
+    /// Whether any requested chunk has not arrived yet.
+    pub fn is_loading(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Bytes the resident chunks take up on the GPU.
+    pub fn resident_bytes(&self) -> usize {
+        self.resident_bytes
+    }
+
+    /// Bytes reserved for the chunks being loaded.
+    pub fn pending_bytes(&self) -> usize {
+        self.pending_bytes
+    }
+
+    /// Takes in finished loads, evicts chunks that left the window or the budget
+    /// and requests the missing ones around `camera`. Returns whether the
+    /// [`Self::table`] changed since the last call.
+    pub fn update(&mut self, camera: Vec3) -> bool {
+        let center = self.chunk_at(camera);
+        let mut changed = self.center != Some(center);
+        if changed {
+            self.center = Some(center);
+            self.cutoff = None;
+        }
+
+        while let Ok((coord, result)) = self.receiver.try_recv() {
+            if let Some(reserved) = self.pending.remove(&coord) {
+                self.pending_bytes -= reserved;
+            }
+            let packed = match result {
+                Ok(Some(packed)) if packed.depth == self.depth => packed,
+                Ok(Some(packed)) => {
+                    log::warn!(
+                        "chunk {coord} has depth {}, expected {}",
+                        packed.depth,
+                        self.depth
+                    );
+                    self.empty_chunk()
+                }
+                Ok(None) => self.empty_chunk(),
+                Err(err) => {
+                    log::warn!("failed to load chunk {coord}: {err}");
+                    self.empty_chunk()
+                }
+            };
+
+            if self.in_window(coord) {
+                self.resident_bytes += packed.size_in_bytes();
+                self.resident.insert(coord, packed);
+                changed = true;
+            }
+        }
+
+        let outside: Vec<IVec3> = self
+            .resident
+            .keys()
+            .copied()
+            .filter(|coord| !self.in_window(*coord))
+            .collect();
+        for coord in outside {
+            self.evict(coord);
+            changed = true;
+        }
+
+        while self.resident_bytes > self.budget && self.evict_farthest(-1) {
+            changed = true;
+        }
+
+        self.request_missing(center) || changed
+    }
+
+    /// Packs the resident chunks for upload with [`crate::renderer::Renderer::set_chunks`].
+    pub fn table(&self) -> ChunkTable {
+        let radius = self.radius as i32;
+        let origin = self.center.unwrap_or(IVec3::ZERO) - radius;
+        let mut table = ChunkTable::new(origin, 2 * self.radius + 1, self.depth, self.voxel_size);
+
+        for (coord, packed) in &self.resident {
+            table.insert(*coord, packed);
+        }
+
+        table
+    }
+
+    /// Starts loading the missing chunks in the window, evicting farther chunks
+    /// to make room for nearer ones. Returns whether any chunk was evicted.
+    fn request_missing(&mut self, center: IVec3) -> bool {
+        let radius = self.radius as i32;
+        let mut missing: Vec<IVec3> = (-radius..=radius)
+            .flat_map(|z| {
+                (-radius..=radius)
+                    .flat_map(move |y| (-radius..=radius).map(move |x| IVec3::new(x, y, z)))
+            })
+            .map(|offset| center + offset)
+            .filter(|coord| !self.resident.contains_key(coord) && !self.pending.contains_key(coord))
+            .collect();
+        missing.sort_by_key(|coord| self.distance(*coord));
+
+        let mut evicted = false;
+        for coord in missing {
+            // Evicting below may lower the cutoff past chunks collected above.
+            if self
+                .cutoff
+                .map_or(false, |cutoff| self.distance(coord) >= cutoff)
+            {
+                break;
+            }
+
+            let path = chunk_path(&self.dir, coord);
+            // A missing or unreadable file reserves nothing, and its load reports
+            // the same outcome as the metadata.
+            let reserved =
+                fs::metadata(&path).map_or(0, |metadata| scene::packed_size_bound(metadata.len()));
+
+            let fits = |manager: &Self| {
+                manager.resident_bytes + manager.pending_bytes + reserved <= manager.budget
+            };
+            while !fits(self) && self.evict_farthest(self.distance(coord)) {
+                evicted = true;
+            }
+            if !fits(self) {
+                break;
+            }
+
+            self.pending.insert(coord, reserved);
+            self.pending_bytes += reserved;
+            let sender = self.sender.clone();
+            self.runtime.spawn(async move {
+                let result = match tokio::fs::read(&path).await {
+                    Ok(bytes) => {
+                        tokio::task::spawn_blocking(move || scene::decode(&bytes).map(Some))
+                            .await
+                            .expect("chunk decoding panicked")
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                    Err(err) => Err(err.into()),
+                };
+                // The manager may have been dropped while the chunk was loading.
+                let _ = sender.send((coord, result));
+            });
+        }
+
+        evicted
+    }
+
+    /// Evicts the farthest resident chunk holding any voxels that is farther than
+    /// `beyond` from the camera's chunk, and stops requesting chunks that far
+    /// away. Returns whether there was one.
+    fn evict_farthest(&mut self, beyond: i32) -> bool {
+        // Empty chunks cost nothing, so evicting them would not help.
+        let Some(farthest) = self
+            .resident
+            .iter()
+            .filter(|(_, packed)| packed.size_in_bytes() > 0)
+            .map(|(coord, _)| *coord)
+            .filter(|coord| self.distance(*coord) > beyond)
+            .max_by_key(|coord| self.distance(*coord))
+        else {
+            return false;
+        };
+
+        let distance = self.distance(farthest);
+        self.cutoff = Some(self.cutoff.map_or(distance, |cutoff| cutoff.min(distance)));
+        self.evict(farthest);
+        true
+    }
+
+    fn evict(&mut self, coord: IVec3) {
+        if let Some(packed) = self.resident.remove(&coord) {
+            self.resident_bytes -= packed.size_in_bytes();
+        }
+    }
+
+    fn in_window(&self, coord: IVec3) -> bool {
+        let center = self.center.unwrap_or(IVec3::ZERO);
+        (coord - center).abs().max_element() <= self.radius as i32
+    }
+
+    /// Squared distance in chunks from the camera's chunk.
+    fn distance(&self, coord: IVec3) -> i32 {
+        (coord - self.center.unwrap_or(IVec3::ZERO)).length_squared()
+    }
+
+    /// Stand-in for a chunk without a file, kept resident so it is not requested
+    /// again.
+    fn empty_chunk(&self) -> PackedSparseVoxelOctree {
+        SparseVoxelOctree::empty(self.depth).pack()
+    }
+}
+
+/// A [`ChunkTable`] resident on the GPU. Residency changes are rare next to
+/// frames, so every update rewrites the whole table.
+pub struct GpuChunks {
+    node_buffer: GrowableBuffer,
+    voxel_buffer: GrowableBuffer,
+    material_buffer: GrowableBuffer,
+    chunk_buffer: GrowableBuffer,
+    lod_buffer: GrowableBuffer,
+}
+
+impl GpuChunks {
+    pub fn new(device: &wgpu::Device, table: &ChunkTable) -> Self {
+        Self {
+            node_buffer: GrowableBuffer::new(device, "node_buffer", &table.nodes),
+            voxel_buffer: GrowableBuffer::new(device, "voxel_buffer", &table.voxels),
+            material_buffer: GrowableBuffer::new(device, "material_buffer", &table.materials),
+            chunk_buffer: GrowableBuffer::new(device, "chunk_buffer", &table.chunks),
+            lod_buffer: GrowableBuffer::new(device, "lod_buffer", &table.lods),
+        }
+    }
+
+    /// The node, voxel, material, chunk and level-of-detail buffers, in binding
+    /// order.
+    pub fn buffers(&self) -> [&wgpu::Buffer; 5] {
+        [
+            &self.node_buffer.buffer,
+            &self.voxel_buffer.buffer,
+            &self.material_buffer.buffer,
+            &self.chunk_buffer.buffer,
+            &self.lod_buffer.buffer,
+        ]
+    }
+
+    /// Uploads `table`. Returns whether a buffer was reallocated, which
+    /// invalidates bind groups built from the old buffers.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        table: &ChunkTable,
+    ) -> bool {
+        let nodes = self
+            .node_buffer
+            .write(device, queue, &table.nodes, vec![0..table.nodes.len()]);
+        let voxels =
+            self.voxel_buffer
+                .write(device, queue, &table.voxels, vec![0..table.voxels.len()]);
+        let materials = self.material_buffer.write(
+            device,
+            queue,
+            &table.materials,
+            vec![0..table.materials.len()],
+        );
+        let chunks =
+            self.chunk_buffer
+                .write(device, queue, &table.chunks, vec![0..table.chunks.len()]);
+
+        let lods = self
+            .lod_buffer
+            .write(device, queue, &table.lods, vec![0..table.lods.len()]);
+
+        nodes || voxels || materials || chunks || lods
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use glam::vec3;
+    use shader::Ray;
+
+    use super::*;
+    use crate::svo::Node;
+
+    const DEPTH: u32 = 2;
+
+    /// A directory in the system's temporary directory, unique to the test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = scene::temp_path(name);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A chunk holding one voxel of its own material with the given albedo.
+    fn chunk(albedo: [f32; 3]) -> PackedSparseVoxelOctree {
+        let mut svo = SparseVoxelOctree::empty(DEPTH);
+        let material = svo.palette_mut().add(Material {
+            albedo,
+            roughness: 1.0,
+            emission: 0.0,
+        });
+        svo.insert(1, 1, 1, Node::Leaf(Some(Voxel { material })), DEPTH);
+        svo.pack()
+    }
+
+    /// Updates `manager` until every requested chunk has arrived.
+    fn settle(manager: &mut ChunkManager, camera: Vec3) {
+        manager.update(camera);
+        while manager.is_loading() {
+            thread::sleep(Duration::from_millis(1));
+            manager.update(camera);
+        }
+    }
+
+    #[test]
+    fn tables_rebase_every_chunk_into_shared_buffers() {
+        let mut table = ChunkTable::new(IVec3::new(-1, 0, 0), 2, DEPTH, 1.0);
+        table.insert(IVec3::new(-1, 0, 0), &chunk([1.0, 0.0, 0.0]));
+        table.insert(IVec3::new(0, 0, 0), &chunk([0.0, 0.0, 1.0]));
+        table.insert(IVec3::new(5, 0, 0), &chunk([0.0, 1.0, 0.0]));
+        // A chunk with the same palette as an earlier one reuses its materials.
+        table.insert(IVec3::new(-1, 1, 0), &chunk([1.0, 0.0, 0.0]));
+
+        let mut constants: ShaderConstants = bytemuck::Zeroable::zeroed();
+        table.write_constants(&mut constants);
+        assert_eq!(constants.world_origin, [-4.0, 0.0, 0.0]);
+        assert_eq!(constants.world_size, 8.0);
+        assert_eq!(table.materials.len(), 2);
+
+        // Rays down -Z through the voxel of each chunk see that chunk's color.
+        for (x, albedo) in [(-2.5, [1.0, 0.0, 0.0]), (1.5, [0.0, 0.0, 1.0])] {
+            let mut ray = Ray {
+                origin: vec3(x, 1.5, 10.0),
+                direction: Vec3::NEG_Z,
+                t: 0.0,
+            };
+            let hit = ray.traverse(
+                &constants,
+                &table.chunks,
+                &table.nodes,
+                &table.lods,
+                &table.voxels,
+                &table.materials,
+            );
+
+            assert!(hit.exists);
+            assert_eq!(hit.material.albedo, albedo);
+            assert_eq!(hit.position.z, 2.0);
+        }
+    }
+
+    #[test]
+    fn splits_trees_into_chunk_files() {
+        let dir = temp_dir("split");
+        let mut svo = SparseVoxelOctree::empty(DEPTH + 1);
+        svo.insert(5, 0, 6, Node::Leaf(Some(Voxel { material: 0 })), DEPTH + 1);
+
+        assert_eq!(split(&svo, DEPTH, &dir).unwrap(), 1);
+        assert_eq!(chunk_depth(&dir).unwrap(), Some(DEPTH));
+        let chunk = scene::load(chunk_path(&dir, IVec3::new(1, 0, 1)))
+            .unwrap()
+            .unpack();
+        assert_eq!(chunk.get(1, 0, 2), Some(&Voxel { material: 0 }));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn streams_chunks_around_the_camera() {
+        let dir = temp_dir("stream");
+        for x in 0..4 {
+            scene::save(
+                &chunk([1.0, 1.0, 1.0]),
+                chunk_path(&dir, IVec3::new(x, 0, 0)),
+            )
+            .unwrap();
+        }
+
+        let mut manager = ChunkManager::new(&dir, DEPTH, 1.0, 1, usize::MAX).unwrap();
+        settle(&mut manager, vec3(2.0, 2.0, 2.0));
+        assert!(manager.is_resident(IVec3::new(0, 0, 0)));
+        assert!(manager.is_resident(IVec3::new(1, 0, 0)));
+        assert!(!manager.is_resident(IVec3::new(2, 0, 0)));
+        // Chunks without a file are resident, just empty.
+        assert!(manager.is_resident(IVec3::new(-1, 0, 0)));
+
+        settle(&mut manager, vec3(10.0, 2.0, 2.0));
+        assert!(!manager.is_resident(IVec3::new(0, 0, 0)));
+        assert!(manager.is_resident(IVec3::new(3, 0, 0)));
+        assert_eq!(manager.table().origin, IVec3::new(1, -1, -1));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_resident_chunks_and_loads_in_flight_within_budget() {
+        let dir = temp_dir("budget");
+        for x in -1..=1 {
+            scene::save(
+                &chunk([1.0, 1.0, 1.0]),
+                chunk_path(&dir, IVec3::new(x, 0, 0)),
+            )
+            .unwrap();
+        }
+        let file_len = fs::metadata(chunk_path(&dir, IVec3::ZERO)).unwrap().len();
+        let budget = scene::packed_size_bound(file_len);
+
+        let mut manager = ChunkManager::new(&dir, DEPTH, 1.0, 1, budget).unwrap();
+        manager.update(vec3(2.0, 2.0, 2.0));
+        // Only the nearest chunk fits while it is loading.
+        assert_eq!(manager.pending_bytes(), budget);
+        settle(&mut manager, vec3(2.0, 2.0, 2.0));
+
+        assert!(manager.is_resident(IVec3::new(0, 0, 0)));
+        assert!(!manager.is_resident(IVec3::new(1, 0, 0)));
+        assert!(!manager.is_resident(IVec3::new(-1, 0, 0)));
+        assert!(manager.resident_bytes() <= budget);
+        assert!(!manager.update(vec3(2.0, 2.0, 2.0)));
+
+        // The chunk the camera moves into evicts the one it left.
+        settle(&mut manager, vec3(6.0, 2.0, 2.0));
+        assert!(manager.is_resident(IVec3::new(1, 0, 0)));
+        assert!(!manager.is_resident(IVec3::new(0, 0, 0)));
+        assert!(manager.resident_bytes() <= budget);
+        // Evicted chunks stay evicted while the camera stays put.
+        assert!(!manager.update(vec3(6.0, 2.0, 2.0)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
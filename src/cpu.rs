@@ -0,0 +1,184 @@
+//! Reference renderer that runs the shader crate on the host, one rayon task per
+//! row. It needs no GPU, which makes it the basis of the golden-image tests and a
+//! convenient place to step through `Ray::traverse` in a debugger.
+
+use glam::{vec2, Vec4};
+use rayon::prelude::*;
+
+use crate::{camera::Camera, image::Image, renderer::Renderer, svo::SparseVoxelOctree};
+
+/// Renders the same frame as [`crate::headless::render`], accumulating `spp`
+/// frames the way the fragment shader does.
+pub fn render(
+    svo: &SparseVoxelOctree,
+    camera: &Camera,
+    width: u32,
+    height: u32,
+    spp: u32,
+) -> Image {
+    let packed_svo = svo.pack();
+    let mut constants = Renderer::initial_constants(packed_svo.depth, width, height);
+    camera.write_constants(&mut constants);
+
+    let mut image = Image::new(width, height);
+    for sample in 0..spp {
+        constants.time = sample as f32;
+        constants.sample_count = sample;
+
+        image
+            .pixels
+            .par_chunks_mut(width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    let frag_coord = vec2(x as f32 + 0.5, y as f32 + 0.5);
+                    let color = shader::render_pixel(
+                        frag_coord,
+                        &constants,
+                        &[packed_svo.root],
+                        &packed_svo.nodes,
+                        &packed_svo.lods,
+                        &packed_svo.voxels,
+                        &packed_svo.materials,
+                    );
+
+                    *pixel = shader::accumulate(Vec4::from(*pixel), color, constants.sample_count)
+                        .to_array();
+                }
+            });
+    }
+
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, f32::consts::PI, path::PathBuf};
+
+    use glam::{vec3, Vec3};
+    use shared::{Material, Voxel};
+
+    use super::*;
+    use crate::{svo::Node, vox};
+
+    const WIDTH: u32 = 48;
+    const HEIGHT: u32 = 32;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    /// Compares against `tests/golden/<name>.png`, allowing one step of 8-bit
+    /// rounding. Run with `UPDATE_GOLDEN=1` to rewrite the golden images after an
+    /// intentional change to the shader.
+    fn assert_golden(name: &str, image: &Image) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(format!("{name}.png"));
+
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            image.save_png(&path).unwrap();
+            return;
+        }
+
+        let golden = Image::load_png(&path).unwrap();
+        assert_eq!((golden.width, golden.height), (image.width, image.height));
+
+        let mismatches = image
+            .to_rgba8()
+            .iter()
+            .zip(golden.to_rgba8())
+            .filter(|(actual, expected)| actual.abs_diff(*expected) > 1)
+            .count();
+        assert_eq!(mismatches, 0, "{name} differs from {}", path.display());
+    }
+
+    #[test]
+    fn gradient_cube_matches_golden() {
+        let svo = SparseVoxelOctree::new(3);
+        let image = render(&svo, &Camera::overview(8.0), WIDTH, HEIGHT, 1);
+
+        assert_golden("gradient_cube", &image);
+    }
+
+    #[test]
+    fn vox_scene_matches_golden() {
+        let svo = vox::load(fixture("scene.vox")).unwrap();
+        let camera = Camera::new(vec3(11.0, 4.0, 24.0), 0.0, -0.1, PI / 3.0);
+
+        let image = render(&svo, &camera, WIDTH, HEIGHT, 1);
+
+        assert_golden("vox_scene", &image);
+    }
+
+    #[test]
+    fn accumulation_averages_every_sample() {
+        let samples = [1.0, 0.25, 0.5, 0.0, 2.0].map(Vec4::splat);
+
+        let average = samples
+            .iter()
+            .enumerate()
+            .fold(Vec4::splat(f32::NAN), |previous, (count, sample)| {
+                shader::accumulate(previous, *sample, count as u32)
+            });
+
+        assert!(average.abs_diff_eq(Vec4::splat(0.75), 1e-6), "{average}");
+    }
+
+    #[test]
+    fn empty_space_renders_the_sky() {
+        let svo = SparseVoxelOctree::empty(3);
+        let camera = Camera::overview(8.0);
+        let image = render(&svo, &camera, WIDTH, HEIGHT, 1);
+
+        let packed_svo = svo.pack();
+        let mut constants = Renderer::initial_constants(packed_svo.depth, WIDTH, HEIGHT);
+        camera.write_constants(&mut constants);
+
+        for (i, pixel) in image.pixels.iter().enumerate() {
+            let (x, y) = (i as u32 % WIDTH, i as u32 / WIDTH);
+            let ray = shader::camera_ray(&constants, vec2(x as f32 + 0.5, y as f32 + 0.5));
+
+            assert_eq!(pixel[..3], shader::sky(ray.direction).to_array());
+        }
+    }
+
+    #[test]
+    fn grazing_rays_cross_every_cell_of_a_finely_split_tree() {
+        // Solid voxels under every other cell of a row keep the cells above them
+        // from merging, so reaching the wall at its end takes one step per cell.
+        let mut svo = SparseVoxelOctree::empty(9);
+        let material = svo.palette_mut().add(Material {
+            albedo: [1.0; 3],
+            roughness: 1.0,
+            emission: 0.0,
+        });
+        let voxel = Node::Leaf(Some(Voxel { material }));
+        for x in (0..512).step_by(2) {
+            svo.insert(x, 0, 0, voxel.clone(), 9);
+        }
+        svo.insert(511, 1, 0, voxel, 9);
+
+        let packed_svo = svo.pack();
+        let constants = Renderer::initial_constants(packed_svo.depth, WIDTH, HEIGHT);
+        let mut ray = shader::Ray {
+            origin: vec3(0.5, 1.5, 0.5),
+            direction: Vec3::X,
+            t: 0.0,
+        };
+        let hit = ray.traverse(
+            &constants,
+            &[packed_svo.root],
+            &packed_svo.nodes,
+            &packed_svo.lods,
+            &packed_svo.voxels,
+            &packed_svo.materials,
+        );
+
+        assert!(hit.exists);
+        assert_eq!(hit.position, vec3(511.0, 1.5, 0.5));
+        assert_eq!(hit.normal, Vec3::NEG_X);
+    }
+}
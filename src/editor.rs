@@ -0,0 +1,419 @@
+use std::cmp::Ordering;
+
+use glam::{ivec3, vec2, IVec3, UVec3, Vec2, Vec3};
+use shared::{ShaderConstants, Voxel};
+use winit::{
+    dpi::PhysicalPosition,
+    event::{
+        ElementState, KeyboardInput, ModifiersState, MouseButton, VirtualKeyCode, WindowEvent,
+    },
+};
+
+use crate::{
+    history::History,
+    svo::{Node, SparseVoxelOctree},
+};
+
+/// The voxel under the cursor.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Pick {
+    pub voxel: UVec3,
+    /// Outward normal of the face the ray entered through, or zero when the ray
+    /// started inside the voxel.
+    pub normal: IVec3,
+    /// Distance from the camera in world units.
+    pub distance: f32,
+}
+
+/// Finds the first solid voxel along the primary ray through `pixel`, built with
+/// the same `shader::camera_ray` that `main_fs` traces.
+pub fn pick(svo: &SparseVoxelOctree, constants: &ShaderConstants, pixel: Vec2) -> Option<Pick> {
+    let ray = shader::camera_ray(constants, pixel);
+    let voxel_size = constants.voxel_size();
+    let origin = (ray.origin - Vec3::from(constants.world_origin)) / voxel_size;
+
+    let hit = svo.raycast(origin, ray.direction, f32::INFINITY)?;
+    Some(Pick {
+        voxel: hit.position,
+        normal: hit.normal,
+        distance: hit.distance * voxel_size,
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Tool {
+    /// Fills empty voxels in front of the picked face.
+    Place,
+    Remove,
+    /// Changes the material of solid voxels only.
+    Paint,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Brush {
+    Cube,
+    Sphere,
+}
+
+/// Voxel editing driven by window events: left click applies the current tool
+/// at the cursor, 1/2/3 select place/remove/paint, B toggles the brush shape,
+/// `[` and `]` change its radius and Q/E cycle through the palette. Each click
+/// is one step of [`Editor::history`], undone with Ctrl+Z and redone with
+/// Ctrl+Y or Ctrl+Shift+Z.
+pub struct Editor {
+    pub tool: Tool,
+    pub brush: Brush,
+    /// Brush radius in voxels; zero edits a single voxel.
+    pub radius: u32,
+    /// Palette index used by place and paint.
+    pub material: u32,
+    pub history: History,
+
+    cursor: Option<PhysicalPosition<f64>>,
+    modifiers: ModifiersState,
+    clicked: bool,
+    material_step: i32,
+    /// Undo steps requested since the last update; negative values redo.
+    undo_steps: i32,
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        Self {
+            tool: Tool::Place,
+            brush: Brush::Cube,
+            radius: 0,
+            material: 0,
+            history: History::new(),
+            cursor: None,
+            modifiers: ModifiersState::empty(),
+            clicked: false,
+            material_step: 0,
+            undo_steps: 0,
+        }
+    }
+
+    /// Returns whether the event was consumed by the editor. Cursor movement is
+    /// tracked but never consumed, so the camera controller sees it too.
+    pub fn process_event(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(keycode),
+                        ..
+                    },
+                ..
+            } if self.modifiers.ctrl() => match keycode {
+                VirtualKeyCode::Z if self.modifiers.shift() => {
+                    self.undo_steps -= 1;
+                    true
+                }
+                VirtualKeyCode::Z => {
+                    self.undo_steps += 1;
+                    true
+                }
+                VirtualKeyCode::Y => {
+                    self.undo_steps -= 1;
+                    true
+                }
+                _ => false,
+            },
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(keycode),
+                        ..
+                    },
+                ..
+            } => {
+                match keycode {
+                    VirtualKeyCode::Key1 => self.tool = Tool::Place,
+                    VirtualKeyCode::Key2 => self.tool = Tool::Remove,
+                    VirtualKeyCode::Key3 => self.tool = Tool::Paint,
+                    VirtualKeyCode::B => {
+                        self.brush = match self.brush {
+                            Brush::Cube => Brush::Sphere,
+                            Brush::Sphere => Brush::Cube,
+                        }
+                    }
+                    VirtualKeyCode::LBracket => self.radius = self.radius.saturating_sub(1),
+                    VirtualKeyCode::RBracket => self.radius += 1,
+                    VirtualKeyCode::Q => self.material_step -= 1,
+                    VirtualKeyCode::E => self.material_step += 1,
+                    _ => return false,
+                }
+                log::info!(
+                    "{:?} with a {:?} brush of radius {}",
+                    self.tool,
+                    self.brush,
+                    self.radius
+                );
+                true
+            }
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+                ..
+            } => {
+                self.clicked = true;
+                true
+            }
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = *modifiers;
+                false
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor = Some(*position);
+                false
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.cursor = None;
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Applies pending undo and redo requests, then a pending click, to `svo`,
+    /// picking with the camera in `constants`. Returns whether the octree was
+    /// edited.
+    pub fn update(&mut self, svo: &mut SparseVoxelOctree, constants: &ShaderConstants) -> bool {
+        let mut edited = false;
+        for _ in 0..self.undo_steps.max(0) {
+            edited |= self.history.undo(svo);
+        }
+        for _ in 0..(-self.undo_steps).max(0) {
+            edited |= self.history.redo(svo);
+        }
+        match self.undo_steps.cmp(&0) {
+            Ordering::Greater => log::info!("undid {} edits", self.undo_steps),
+            Ordering::Less => log::info!("redid {} edits", -self.undo_steps),
+            Ordering::Equal => {}
+        }
+        self.undo_steps = 0;
+
+        let palette_size = svo.palette().len() as i32;
+        if self.material_step != 0 && palette_size > 0 {
+            self.material =
+                (self.material as i32 + self.material_step).rem_euclid(palette_size) as u32;
+            log::info!("material {}", self.material);
+        }
+        self.material_step = 0;
+
+        let (true, Some(cursor)) = (std::mem::take(&mut self.clicked), self.cursor) else {
+            return edited;
+        };
+        match pick(svo, constants, vec2(cursor.x as f32, cursor.y as f32)) {
+            Some(pick) => {
+                self.apply(svo, &pick);
+                true
+            }
+            None => edited,
+        }
+    }
+
+    /// Applies the current tool and brush around `pick`. Voxels of the brush that
+    /// fall outside the tree are skipped. The whole stroke is one undo step.
+    pub fn apply(&mut self, svo: &mut SparseVoxelOctree, pick: &Pick) {
+        let center = match self.tool {
+            Tool::Place => pick.voxel.as_ivec3() + pick.normal,
+            Tool::Remove | Tool::Paint => pick.voxel.as_ivec3(),
+        };
+        let size = 1 << svo.depth();
+        let radius = self.radius as i32;
+        let voxel = Voxel {
+            material: self.material,
+        };
+
+        self.history.begin_group();
+        for z in -radius..=radius {
+            for y in -radius..=radius {
+                for x in -radius..=radius {
+                    let offset = ivec3(x, y, z);
+                    if self.brush == Brush::Sphere && offset.length_squared() > radius * radius {
+                        continue;
+                    }
+
+                    let position = center + offset;
+                    if position.cmplt(IVec3::ZERO).any() || position.cmpge(IVec3::splat(size)).any()
+                    {
+                        continue;
+                    }
+
+                    let p = position.as_uvec3();
+                    let solid = svo.get(p.x, p.y, p.z).is_some();
+                    match self.tool {
+                        Tool::Place if !solid => {
+                            let depth = svo.depth();
+                            self.history
+                                .insert(svo, p.x, p.y, p.z, Node::Leaf(Some(voxel)), depth)
+                        }
+                        Tool::Remove if solid => self.history.remove(svo, p.x, p.y, p.z),
+                        Tool::Paint if solid => {
+                            let depth = svo.depth();
+                            self.history
+                                .insert(svo, p.x, p.y, p.z, Node::Leaf(Some(voxel)), depth)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        self.history.end_group();
+    }
+}
+
+impl Default for Editor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+
+    use super::*;
+    use crate::{camera::Camera, renderer::Renderer};
+
+    const DEPTH: u32 = 3;
+
+    fn constants(svo: &SparseVoxelOctree, camera: &Camera) -> ShaderConstants {
+        let mut constants = Renderer::initial_constants(svo.depth(), 64, 64);
+        camera.write_constants(&mut constants);
+        constants
+    }
+
+    fn solid(svo: &SparseVoxelOctree) -> Vec<UVec3> {
+        let size = 1 << svo.depth();
+        (0..size * size * size)
+            .map(|i| UVec3::new(i % size, i / size % size, i / size / size))
+            .filter(|p| svo.get(p.x, p.y, p.z).is_some())
+            .collect()
+    }
+
+    #[test]
+    fn picks_the_voxel_and_face_under_the_cursor() {
+        let svo = SparseVoxelOctree::new(DEPTH);
+        let camera = Camera::new(vec3(4.5, 6.5, 20.0), 0.0, 0.0, 1.0);
+
+        let center = pick(&svo, &constants(&svo, &camera), vec2(32.0, 32.0)).unwrap();
+        assert_eq!(center.voxel, UVec3::new(4, 6, 7));
+        assert_eq!(center.normal, IVec3::Z);
+        assert!((center.distance - 12.0).abs() < 1e-3);
+
+        // Past the edge of the cube the ray misses.
+        assert_eq!(
+            pick(&svo, &constants(&svo, &camera), vec2(63.0, 32.0)),
+            None
+        );
+    }
+
+    #[test]
+    fn picks_side_faces_of_voxels_behind_empty_space() {
+        let mut svo = SparseVoxelOctree::empty(DEPTH);
+        svo.insert(6, 1, 2, Node::Leaf(Some(Voxel { material: 0 })), DEPTH);
+        let camera = Camera::new(vec3(-3.0, 1.5, 2.5), std::f32::consts::FRAC_PI_2, 0.0, 1.0);
+
+        let hit = pick(&svo, &constants(&svo, &camera), vec2(32.0, 32.0)).unwrap();
+        assert_eq!(hit.voxel, UVec3::new(6, 1, 2));
+        assert_eq!(hit.normal, IVec3::NEG_X);
+    }
+
+    #[test]
+    fn place_fills_the_cell_in_front_of_the_face() {
+        let mut svo = SparseVoxelOctree::empty(DEPTH);
+        let mut editor = Editor {
+            material: 3,
+            ..Editor::new()
+        };
+
+        editor.apply(
+            &mut svo,
+            &Pick {
+                voxel: UVec3::new(2, 2, 2),
+                normal: IVec3::Y,
+                distance: 1.0,
+            },
+        );
+
+        assert_eq!(solid(&svo), [UVec3::new(2, 3, 2)]);
+        assert_eq!(svo.get(2, 3, 2), Some(&Voxel { material: 3 }));
+    }
+
+    #[test]
+    fn sphere_brushes_remove_a_ball_clipped_to_the_tree() {
+        let mut svo = SparseVoxelOctree::new(DEPTH);
+        let mut editor = Editor {
+            tool: Tool::Remove,
+            brush: Brush::Sphere,
+            radius: 2,
+            ..Editor::new()
+        };
+
+        editor.apply(
+            &mut svo,
+            &Pick {
+                voxel: UVec3::new(0, 4, 4),
+                normal: IVec3::NEG_X,
+                distance: 1.0,
+            },
+        );
+
+        // A radius 2 ball has 33 cells; the 23 with x >= 0 are inside the tree.
+        assert_eq!(solid(&svo).len(), 512 - 23);
+        assert!(svo.get(2, 4, 4).is_none());
+        assert!(svo.get(2, 5, 4).is_some());
+        assert!(svo.get(1, 5, 5).is_none());
+    }
+
+    #[test]
+    fn paint_recolors_only_solid_voxels() {
+        let mut svo = SparseVoxelOctree::empty(DEPTH);
+        svo.insert(1, 1, 1, Node::Leaf(Some(Voxel { material: 0 })), DEPTH);
+        let mut editor = Editor {
+            tool: Tool::Paint,
+            radius: 1,
+            material: 7,
+            ..Editor::new()
+        };
+
+        editor.apply(
+            &mut svo,
+            &Pick {
+                voxel: UVec3::new(1, 1, 1),
+                normal: IVec3::X,
+                distance: 1.0,
+            },
+        );
+
+        assert_eq!(solid(&svo), [UVec3::new(1, 1, 1)]);
+        assert_eq!(svo.get(1, 1, 1), Some(&Voxel { material: 7 }));
+    }
+
+    #[test]
+    fn a_brush_stroke_undoes_in_one_step() {
+        let mut svo = SparseVoxelOctree::new(DEPTH);
+        let original = svo.root().clone();
+        let mut editor = Editor {
+            tool: Tool::Remove,
+            radius: 1,
+            ..Editor::new()
+        };
+        let pick = Pick {
+            voxel: UVec3::new(3, 3, 3),
+            normal: IVec3::Y,
+            distance: 1.0,
+        };
+
+        editor.apply(&mut svo, &pick);
+        assert_eq!(solid(&svo).len(), 512 - 27);
+
+        assert!(editor.history.undo(&mut svo));
+        assert!(*svo.root() == original);
+        assert!(!editor.history.undo(&mut svo));
+    }
+}
@@ -0,0 +1,527 @@
+//! Incremental upload of a [`SparseVoxelOctree`] to the GPU.
+//!
+//! Unlike [`SparseVoxelOctree::pack`], which lays out a fresh buffer every time,
+//! [`GpuOctree`] gives each branch a stable slot in the node buffer. Edits only
+//! re-pack the subtrees overlapping the boxes reported by
+//! [`SparseVoxelOctree::take_dirty`], slots of removed branches go on a free list
+//! for reuse, and only the slots that actually changed are written with
+//! `queue.write_buffer`.
+
+use std::{collections::HashMap, mem::size_of, ops::Range};
+
+use bytemuck::{Pod, Zeroable};
+use glam::UVec3;
+use shared::{Material, PackedNode, Voxel};
+use wgpu::util::DeviceExt;
+
+use crate::svo::{self, Node, SparseVoxelOctree};
+
+const EMPTY: PackedNode = PackedNode(u32::MAX);
+const LEAF: u32 = 1 << 31;
+
+/// Smallest buffer allocated, so empty scenes still satisfy the minimum binding
+/// sizes of the bind group layout.
+pub(crate) const MIN_BUFFER_SIZE: usize = size_of::<[PackedNode; 8]>();
+
+/// Host copy of the node, voxel and material buffers, plus the bookkeeping
+/// needed to update them in place.
+struct OctreeMirror {
+    root: PackedNode,
+    nodes: Vec<[PackedNode; 8]>,
+    /// Level-of-detail entry of every node slot, see [`svo::lod`].
+    lods: Vec<PackedNode>,
+    free_slots: Vec<u32>,
+    /// Voxels are interned, so leaves with equal contents share one entry and
+    /// editing a leaf never needs to free anything.
+    voxels: Vec<Voxel>,
+    voxel_indices: HashMap<Voxel, u32>,
+    materials: Vec<Material>,
+}
+
+/// Indices written by one [`OctreeMirror::sync`].
+#[derive(Default)]
+struct Changes {
+    /// Slots whose children or level-of-detail entry changed.
+    nodes: Vec<u32>,
+    voxels: Vec<u32>,
+    materials: Vec<u32>,
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.voxels.is_empty() && self.materials.is_empty()
+    }
+}
+
+impl OctreeMirror {
+    fn new(svo: &SparseVoxelOctree) -> Self {
+        let mut mirror = Self {
+            root: EMPTY,
+            nodes: vec![],
+            lods: vec![],
+            free_slots: vec![],
+            voxels: vec![],
+            voxel_indices: HashMap::new(),
+            materials: vec![],
+        };
+        mirror.sync(svo, None);
+
+        mirror
+    }
+
+    /// Brings the mirror up to date with `svo`, revisiting only the nodes that
+    /// overlap `dirty`, or every node when it is `None`.
+    fn sync(&mut self, svo: &SparseVoxelOctree, dirty: Option<&[(UVec3, UVec3)]>) -> Changes {
+        let mut changes = Changes::default();
+
+        if dirty.map_or(true, |dirty| !dirty.is_empty()) {
+            let size = 2_u32.pow(svo.depth());
+            self.root = self.sync_node(
+                svo.root(),
+                self.root,
+                UVec3::ZERO,
+                size,
+                dirty,
+                &mut changes,
+            );
+        }
+
+        let materials = svo.palette().materials();
+        self.materials.resize(materials.len(), Material::zeroed());
+        for (index, (uploaded, material)) in self.materials.iter_mut().zip(materials).enumerate() {
+            if uploaded != material {
+                *uploaded = *material;
+                changes.materials.push(index as u32);
+            }
+        }
+
+        changes
+    }
+
+    /// Packs `node`, which covers `size` voxels from `min` and was last packed as
+    /// `old`, reusing `old`'s slot when both are branches.
+    fn sync_node(
+        &mut self,
+        node: &Node,
+        old: PackedNode,
+        min: UVec3,
+        size: u32,
+        dirty: Option<&[(UVec3, UVec3)]>,
+        changes: &mut Changes,
+    ) -> PackedNode {
+        let max = min + size;
+        if let Some(dirty) = dirty {
+            if !dirty.iter().any(|(dirty_min, dirty_max)| {
+                dirty_min.cmplt(max).all() && dirty_max.cmpgt(min).all()
+            }) {
+                return old;
+            }
+        }
+
+        match node {
+            Node::Leaf(voxel) => {
+                self.free(old);
+                voxel.map_or(EMPTY, |voxel| {
+                    PackedNode(self.intern(voxel, changes) | LEAF)
+                })
+            }
+            Node::Branch { children } => {
+                // A freshly allocated slot has no previous contents to compare
+                // against, so everything below it must be packed.
+                let (slot, dirty) = if old.is_leaf() {
+                    (self.allocate(changes), None)
+                } else {
+                    (old.0, dirty)
+                };
+
+                let mut packed = self.nodes[slot as usize];
+                let child_size = size / 2;
+                for (i, child) in children.iter().enumerate() {
+                    let offset = UVec3::new(i as u32 & 1, (i as u32 >> 1) & 1, (i as u32 >> 2) & 1);
+                    packed[i] = self.sync_node(
+                        child,
+                        packed[i],
+                        min + offset * child_size,
+                        child_size,
+                        dirty,
+                        changes,
+                    );
+                }
+
+                // A grandchild edit can change the level of detail without
+                // touching this node's children.
+                let lod = svo::lod(&packed, &self.lods, &self.voxels);
+                if packed != self.nodes[slot as usize] || lod != self.lods[slot as usize] {
+                    self.nodes[slot as usize] = packed;
+                    self.lods[slot as usize] = lod;
+                    changes.nodes.push(slot);
+                }
+
+                PackedNode(slot)
+            }
+        }
+    }
+
+    /// Takes a node slot from the free list, or appends one, and clears it.
+    fn allocate(&mut self, changes: &mut Changes) -> u32 {
+        let slot = self.free_slots.pop().unwrap_or_else(|| {
+            self.nodes.push([EMPTY; 8]);
+            self.lods.push(EMPTY);
+            self.nodes.len() as u32 - 1
+        });
+        self.nodes[slot as usize] = [EMPTY; 8];
+        self.lods[slot as usize] = EMPTY;
+        changes.nodes.push(slot);
+
+        slot
+    }
+
+    /// Returns the slots of the subtree under `packed` to the free list.
+    fn free(&mut self, packed: PackedNode) {
+        if !packed.is_leaf() {
+            for child in self.nodes[packed.0 as usize] {
+                self.free(child);
+            }
+            self.free_slots.push(packed.0);
+        }
+    }
+
+    fn intern(&mut self, voxel: Voxel, changes: &mut Changes) -> u32 {
+        *self.voxel_indices.entry(voxel).or_insert_with(|| {
+            self.voxels.push(voxel);
+            changes.voxels.push(self.voxels.len() as u32 - 1);
+            self.voxels.len() as u32 - 1
+        })
+    }
+}
+
+/// Sorts `indices` and groups consecutive ones, so each run becomes one write.
+pub(crate) fn runs(mut indices: Vec<u32>) -> Vec<Range<usize>> {
+    indices.sort_unstable();
+    indices.dedup();
+
+    let mut runs: Vec<Range<usize>> = vec![];
+    for index in indices.into_iter().map(|index| index as usize) {
+        match runs.last_mut() {
+            Some(run) if run.end == index => run.end += 1,
+            _ => runs.push(index..index + 1),
+        }
+    }
+
+    runs
+}
+
+/// Largest buffer `device` can bind as a storage buffer.
+pub fn max_storage_buffer_size(device: &wgpu::Device) -> u64 {
+    let limits = device.limits();
+    (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size)
+}
+
+/// A storage buffer that reallocates, doubling, when its contents outgrow it.
+/// Doubling stops at [`max_storage_buffer_size`], so contents that fit the limit
+/// always fit the buffer.
+pub(crate) struct GrowableBuffer {
+    label: &'static str,
+    pub(crate) buffer: wgpu::Buffer,
+}
+
+impl GrowableBuffer {
+    pub(crate) fn new<T: Pod>(device: &wgpu::Device, label: &'static str, data: &[T]) -> Self {
+        let mut contents = bytemuck::cast_slice(data).to_vec();
+        contents.resize(contents.len().max(MIN_BUFFER_SIZE), 0);
+
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(label),
+            contents: &contents,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        Self { label, buffer }
+    }
+
+    /// Writes the `runs` of `data` that changed. Returns `true` if the buffer had
+    /// to be reallocated, in which case all of `data` was written and bind groups
+    /// referring to the old buffer must be recreated.
+    pub(crate) fn write<T: Pod>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: &[T],
+        runs: Vec<Range<usize>>,
+    ) -> bool {
+        let bytes = bytemuck::cast_slice(data);
+
+        if bytes.len() as u64 > self.buffer.size() {
+            self.buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(self.label),
+                size: (bytes.len() as u64)
+                    .max((self.buffer.size() * 2).min(max_storage_buffer_size(device))),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            queue.write_buffer(&self.buffer, 0, bytes);
+
+            return true;
+        }
+
+        for run in runs {
+            let range = run.start * size_of::<T>()..run.end * size_of::<T>();
+            queue.write_buffer(&self.buffer, range.start as u64, &bytes[range]);
+        }
+
+        false
+    }
+}
+
+/// An octree resident on the GPU, updated in place as the host tree is edited.
+pub struct GpuOctree {
+    mirror: OctreeMirror,
+    node_buffer: GrowableBuffer,
+    lod_buffer: GrowableBuffer,
+    voxel_buffer: GrowableBuffer,
+    material_buffer: GrowableBuffer,
+    /// Chunk table of a single entry, the root.
+    chunk_buffer: GrowableBuffer,
+}
+
+impl GpuOctree {
+    pub fn new(device: &wgpu::Device, svo: &SparseVoxelOctree) -> Self {
+        let mirror = OctreeMirror::new(svo);
+
+        Self {
+            node_buffer: GrowableBuffer::new(device, "node_buffer", &mirror.nodes),
+            lod_buffer: GrowableBuffer::new(device, "lod_buffer", &mirror.lods),
+            voxel_buffer: GrowableBuffer::new(device, "voxel_buffer", &mirror.voxels),
+            material_buffer: GrowableBuffer::new(device, "material_buffer", &mirror.materials),
+            chunk_buffer: GrowableBuffer::new(device, "chunk_buffer", &[mirror.root]),
+            mirror,
+        }
+    }
+
+    /// Root node, uploaded as the only entry of the chunk table.
+    pub fn root(&self) -> PackedNode {
+        self.mirror.root
+    }
+
+    pub fn node_buffer(&self) -> &wgpu::Buffer {
+        &self.node_buffer.buffer
+    }
+
+    pub fn lod_buffer(&self) -> &wgpu::Buffer {
+        &self.lod_buffer.buffer
+    }
+
+    pub fn voxel_buffer(&self) -> &wgpu::Buffer {
+        &self.voxel_buffer.buffer
+    }
+
+    pub fn material_buffer(&self) -> &wgpu::Buffer {
+        &self.material_buffer.buffer
+    }
+
+    pub fn chunk_buffer(&self) -> &wgpu::Buffer {
+        &self.chunk_buffer.buffer
+    }
+
+    /// The node, voxel, material, chunk and level-of-detail buffers, in binding
+    /// order.
+    pub fn buffers(&self) -> [&wgpu::Buffer; 5] {
+        [
+            self.node_buffer(),
+            self.voxel_buffer(),
+            self.material_buffer(),
+            self.chunk_buffer(),
+            self.lod_buffer(),
+        ]
+    }
+
+    /// Uploads the edits made to `svo` since the last call, along with any
+    /// palette changes. Returns whether anything changed and whether a buffer was
+    /// reallocated, which invalidates bind groups built from the old buffers.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        svo: &mut SparseVoxelOctree,
+    ) -> (bool, bool) {
+        let dirty = svo.take_dirty();
+        let changes = self.mirror.sync(svo, Some(&dirty));
+        if changes.is_empty() {
+            return (false, false);
+        }
+
+        let mirror = &self.mirror;
+        let node_runs = runs(changes.nodes);
+        let lods = self
+            .lod_buffer
+            .write(device, queue, &mirror.lods, node_runs.clone());
+        let nodes = self
+            .node_buffer
+            .write(device, queue, &mirror.nodes, node_runs);
+        let voxels = self
+            .voxel_buffer
+            .write(device, queue, &mirror.voxels, runs(changes.voxels));
+        let materials =
+            self.material_buffer
+                .write(device, queue, &mirror.materials, runs(changes.materials));
+        self.chunk_buffer
+            .write(device, queue, &[mirror.root], vec![0..1]);
+
+        (true, nodes || lods || voxels || materials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    const DEPTH: u32 = 4;
+
+    /// Point query through the mirror, descending it like the shader.
+    fn get(mirror: &OctreeMirror, depth: u32, position: UVec3) -> Option<Voxel> {
+        let mut node = mirror.root;
+        let mut size = 1 << depth;
+        while !node.is_leaf() {
+            size /= 2;
+            let index = (position.x & size != 0) as usize
+                | ((position.y & size != 0) as usize) << 1
+                | ((position.z & size != 0) as usize) << 2;
+            node = mirror.nodes[node.0 as usize][index];
+        }
+
+        (!node.is_empty()).then(|| mirror.voxels[(node.0 & !LEAF) as usize])
+    }
+
+    fn assert_mirrors(mirror: &OctreeMirror, svo: &SparseVoxelOctree) {
+        // The root's level of detail depends on every entry below it.
+        let packed = svo.pack();
+        let lod = |root: PackedNode, lods: &[PackedNode], voxels: &[Voxel]| {
+            let drawn = if root.is_leaf() {
+                root
+            } else {
+                lods[root.0 as usize]
+            };
+            (!drawn.is_empty()).then(|| voxels[(drawn.0 & !LEAF) as usize])
+        };
+        assert_eq!(
+            lod(mirror.root, &mirror.lods, &mirror.voxels),
+            lod(packed.root, &packed.lods, &packed.voxels)
+        );
+
+        let size = 1 << svo.depth();
+        for i in 0..size * size * size {
+            let position = UVec3::new(i % size, i / size % size, i / size / size);
+            assert_eq!(
+                get(mirror, svo.depth(), position),
+                svo.get(position.x, position.y, position.z).copied(),
+                "at {position}"
+            );
+        }
+    }
+
+    fn sync(mirror: &mut OctreeMirror, svo: &mut SparseVoxelOctree) -> Changes {
+        let dirty = svo.take_dirty();
+        mirror.sync(svo, Some(&dirty))
+    }
+
+    #[test]
+    fn random_edits_keep_the_mirror_in_sync() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut svo = SparseVoxelOctree::new(DEPTH);
+        let mut mirror = OctreeMirror::new(&svo);
+        svo.take_dirty();
+
+        for _ in 0..50 {
+            for _ in 0..rng.gen_range(1..20) {
+                let [x, y, z] = [(); 3].map(|_| rng.gen_range(0..1 << DEPTH));
+                match rng.gen_range(0..4) {
+                    0 => svo.remove(x, y, z),
+                    1 => {
+                        let size = UVec3::new(
+                            rng.gen_range(1..6),
+                            rng.gen_range(1..6),
+                            rng.gen_range(1..6),
+                        );
+                        svo.clear_box(UVec3::new(x, y, z), UVec3::new(x, y, z) + size);
+                    }
+                    _ => {
+                        let voxel = Voxel {
+                            material: rng.gen_range(0..4),
+                        };
+                        svo.insert(
+                            x,
+                            y,
+                            z,
+                            Node::Leaf(Some(voxel)),
+                            rng.gen_range(DEPTH - 2..=DEPTH),
+                        );
+                    }
+                }
+            }
+
+            sync(&mut mirror, &mut svo);
+            assert_mirrors(&mirror, &svo);
+        }
+    }
+
+    #[test]
+    fn edits_rewrite_only_the_changed_path() {
+        let mut svo = SparseVoxelOctree::new(DEPTH);
+        let mut mirror = OctreeMirror::new(&svo);
+        svo.take_dirty();
+
+        svo.insert(3, 9, 14, Node::Leaf(Some(Voxel { material: 0 })), DEPTH);
+        let changes = sync(&mut mirror, &mut svo);
+
+        // Only the leaf's parent changes; its ancestors still point at the same slots.
+        assert_eq!(changes.nodes.len(), 1);
+        assert!(changes.voxels.is_empty());
+        assert!(sync(&mut mirror, &mut svo).is_empty());
+        assert_mirrors(&mirror, &svo);
+    }
+
+    #[test]
+    fn removed_branches_free_their_slots_for_reuse() {
+        let mut svo = SparseVoxelOctree::empty(DEPTH);
+        let mut mirror = OctreeMirror::new(&svo);
+
+        svo.insert(1, 2, 3, Node::Leaf(Some(Voxel { material: 0 })), DEPTH);
+        sync(&mut mirror, &mut svo);
+        assert_eq!(mirror.nodes.len(), DEPTH as usize);
+
+        svo.remove(1, 2, 3);
+        sync(&mut mirror, &mut svo);
+        assert_eq!(mirror.free_slots.len(), DEPTH as usize);
+        assert!(mirror.root.is_empty());
+
+        svo.insert(14, 0, 7, Node::Leaf(Some(Voxel { material: 1 })), DEPTH);
+        sync(&mut mirror, &mut svo);
+        assert_eq!(mirror.nodes.len(), DEPTH as usize);
+        assert!(mirror.free_slots.is_empty());
+        assert_mirrors(&mirror, &svo);
+    }
+
+    #[test]
+    fn palette_edits_are_uploaded_without_touching_nodes() {
+        let mut svo = SparseVoxelOctree::new(2);
+        let mut mirror = OctreeMirror::new(&svo);
+        svo.take_dirty();
+
+        let mut material = *svo.palette().get(5).unwrap();
+        material.albedo = [1.0, 0.0, 0.0];
+        svo.palette_mut().set(5, material);
+        let changes = sync(&mut mirror, &mut svo);
+
+        assert_eq!(changes.materials, [5]);
+        assert!(changes.nodes.is_empty());
+        assert_eq!(mirror.materials[5], material);
+    }
+
+    #[test]
+    fn runs_merge_consecutive_indices() {
+        assert_eq!(runs(vec![7, 3, 4, 5, 9, 3, 10]), [3..6, 7..8, 9..11]);
+        assert!(runs(vec![]).is_empty());
+    }
+}
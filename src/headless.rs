@@ -0,0 +1,200 @@
+//! Offscreen rendering for batch jobs on machines without a display, including
+//! software Vulkan implementations such as lavapipe.
+
+use std::{error::Error, path::PathBuf, sync::mpsc};
+
+use crate::{camera::Camera, cpu, image::Image, renderer::Renderer, scene, svo::SparseVoxelOctree};
+
+const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+pub struct RenderOptions {
+    pub scene: PathBuf,
+    pub out: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub spp: u32,
+    /// Trace on the host with [`cpu::render`] instead of a Vulkan device.
+    pub cpu: bool,
+}
+
+impl RenderOptions {
+    pub const USAGE: &'static str =
+        "usage: voxel-tracer render <scene.vox|scene file> [--out frame.png] [--width N] [--height N] [--spp N] [--cpu]";
+
+    /// Parses the arguments following the `render` subcommand.
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut scene = None;
+        let mut options = RenderOptions {
+            scene: PathBuf::new(),
+            out: PathBuf::from("frame.png"),
+            width: 1920,
+            height: 1080,
+            spp: 1,
+            cpu: false,
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("missing value for {arg}"));
+            let mut number = || -> Result<u32, String> {
+                let value = value()?;
+                match value.parse() {
+                    Ok(0) | Err(_) => Err(format!("{arg} expects a positive integer, got {value}")),
+                    Ok(n) => Ok(n),
+                }
+            };
+
+            match arg.as_str() {
+                "--out" => options.out = value()?.into(),
+                "--width" => options.width = number()?,
+                "--height" => options.height = number()?,
+                "--spp" => options.spp = number()?,
+                "--cpu" => options.cpu = true,
+                _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+                _ if scene.is_none() => scene = Some(arg.into()),
+                _ => return Err(format!("unexpected argument {arg}")),
+            }
+        }
+
+        options.scene = scene.ok_or("missing scene file")?;
+        Ok(options)
+    }
+}
+
+pub async fn run(options: &RenderOptions) -> Result<(), Box<dyn Error>> {
+    let svo = scene::load_octree(&options.scene)?;
+    // Packs the whole scene twice, so only when asked for.
+    if log::log_enabled!(log::Level::Debug) {
+        log::debug!(
+            "DAG packing would shrink the scene {:.1}x",
+            svo.dag_compression_ratio()
+        );
+    }
+    let camera = Camera::overview((1 << svo.depth()) as f32);
+
+    let image = if options.cpu {
+        cpu::render(&svo, &camera, options.width, options.height, options.spp)
+    } else {
+        render(&svo, &camera, options.width, options.height, options.spp).await?
+    };
+    image.save_png(&options.out)
+}
+
+/// Traces frames into an offscreen texture until `spp` samples have accumulated.
+pub async fn render(
+    svo: &SparseVoxelOctree,
+    camera: &Camera,
+    width: u32,
+    height: u32,
+    spp: u32,
+) -> Result<Image, Box<dyn Error>> {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::VULKAN,
+        ..Default::default()
+    });
+
+    let mut adapter_options = wgpu::RequestAdapterOptions {
+        power_preference: wgpu::PowerPreference::default(),
+        compatible_surface: None,
+        force_fallback_adapter: false,
+    };
+    let adapter = match instance.request_adapter(&adapter_options).await {
+        Some(adapter) => adapter,
+        None => {
+            adapter_options.force_fallback_adapter = true;
+            instance
+                .request_adapter(&adapter_options)
+                .await
+                .ok_or("no Vulkan adapter available")?
+        }
+    };
+
+    let (device, queue) = Renderer::request_device(&adapter).await?;
+    let mut renderer = Renderer::new(device, queue, FORMAT, width, height, svo);
+    camera.write_constants(&mut renderer.shader_constants);
+
+    let target = renderer.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("headless_target"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    });
+    let view = target.create_view(&wgpu::TextureViewDescriptor::default());
+
+    while renderer.sample_count() < spp {
+        renderer.shader_constants.time = renderer.sample_count() as f32;
+        renderer.render(&view);
+    }
+
+    let mut image = Image::new(width, height);
+    for (pixel, rgba) in image
+        .pixels
+        .iter_mut()
+        .zip(read_texture(&renderer, &target)?.chunks_exact(4))
+    {
+        for (channel, value) in pixel.iter_mut().zip(rgba) {
+            *channel = *value as f32 / 255.0;
+        }
+    }
+
+    Ok(image)
+}
+
+/// Copies an RGBA8 texture back to the host, dropping the row padding wgpu
+/// requires for texture-to-buffer copies.
+fn read_texture(renderer: &Renderer, texture: &wgpu::Texture) -> Result<Vec<u8>, Box<dyn Error>> {
+    let size = texture.size();
+    let row_bytes = size.width * 4;
+    let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let padded_row_bytes = (row_bytes + alignment - 1) / alignment * alignment;
+
+    let buffer = renderer.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("readback_buffer"),
+        size: (padded_row_bytes * size.height) as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = renderer
+        .device
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Readback Encoder"),
+        });
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_row_bytes),
+                rows_per_image: Some(size.height),
+            },
+        },
+        size,
+    );
+    renderer.queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    let (sender, receiver) = mpsc::channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    renderer.device.poll(wgpu::Maintain::Wait);
+    receiver.recv()??;
+
+    let padded = slice.get_mapped_range();
+    let pixels = padded
+        .chunks_exact(padded_row_bytes as usize)
+        .flat_map(|row| &row[..row_bytes as usize])
+        .copied()
+        .collect();
+
+    Ok(pixels)
+}
@@ -0,0 +1,426 @@
+//! Undo and redo for octree edits.
+//!
+//! Every edit made through a [`History`] first copies the subtrees it is about
+//! to replace, so undoing it is a matter of inserting those copies back. Edits
+//! between [`History::begin_group`] and [`History::end_group`] form a single
+//! step, which is how a brush stroke touching many voxels undoes at once.
+
+use std::{collections::VecDeque, mem::size_of};
+
+use glam::UVec3;
+
+use crate::svo::{Node, SparseVoxelOctree};
+
+/// Memory kept for undo steps unless [`History::with_budget`] says otherwise.
+const DEFAULT_BUDGET: usize = 64 << 20;
+
+/// One replaced subtree: the node `depth` levels below the root containing
+/// `position`, before and after the edit.
+struct Change {
+    position: UVec3,
+    depth: u32,
+    before: Node,
+    after: Node,
+    /// Depth of the tree before the edit deepened it, in which case `before` is
+    /// the whole tree.
+    grown_from: Option<u32>,
+}
+
+#[derive(Default)]
+struct Step {
+    changes: Vec<Change>,
+    bytes: usize,
+}
+
+impl Step {
+    fn push(&mut self, change: Change) {
+        self.bytes += size_of::<Change>() + heap_size(&change.before) + heap_size(&change.after);
+        self.changes.push(change);
+    }
+}
+
+/// Bytes owned by the branches of `node`.
+fn heap_size(node: &Node) -> usize {
+    match node {
+        Node::Branch { children } => {
+            size_of::<[Node; 8]>() + children.iter().map(heap_size).sum::<usize>()
+        }
+        Node::Leaf(_) => 0,
+    }
+}
+
+pub struct History {
+    undo: VecDeque<Step>,
+    redo: Vec<Step>,
+    /// Step being assembled between `begin_group` and `end_group`.
+    group: Option<Step>,
+    budget: usize,
+    bytes: usize,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::with_budget(DEFAULT_BUDGET)
+    }
+
+    /// A history that forgets its oldest steps once the subtrees it keeps for
+    /// undo and redo take more than `budget` bytes. The most recent step is kept
+    /// even if it alone exceeds the budget.
+    pub fn with_budget(budget: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: vec![],
+            group: None,
+            budget,
+            bytes: 0,
+        }
+    }
+
+    /// Starts collecting edits into one undoable step.
+    pub fn begin_group(&mut self) {
+        self.end_group();
+        self.group = Some(Step::default());
+    }
+
+    /// Closes the step started by [`Self::begin_group`]. Empty groups are dropped.
+    pub fn end_group(&mut self) {
+        if let Some(step) = self.group.take() {
+            self.commit(step);
+        }
+    }
+
+    /// [`SparseVoxelOctree::insert`], recorded for undo. An edit deeper than the
+    /// tree deepens it as usual, and undoing it restores the shallower tree.
+    pub fn insert(
+        &mut self,
+        svo: &mut SparseVoxelOctree,
+        x: u32,
+        y: u32,
+        z: u32,
+        node: Node,
+        depth: u32,
+    ) {
+        let grown_from = (depth > svo.depth()).then_some(svo.depth());
+        let change = Change {
+            position: UVec3::new(x, y, z),
+            depth,
+            before: match grown_from {
+                Some(_) => svo.root().clone(),
+                None => svo.subtree(x, y, z, depth),
+            },
+            after: node.clone(),
+            grown_from,
+        };
+        svo.insert(x, y, z, node, depth);
+
+        self.record(change);
+    }
+
+    /// [`SparseVoxelOctree::remove`], recorded for undo.
+    pub fn remove(&mut self, svo: &mut SparseVoxelOctree, x: u32, y: u32, z: u32) {
+        self.insert(svo, x, y, z, Node::Leaf(None), svo.depth());
+    }
+
+    /// [`SparseVoxelOctree::clear_box`], recorded for undo. Only the largest
+    /// subtrees inside the box that hold anything are copied.
+    pub fn clear_box(&mut self, svo: &mut SparseVoxelOctree, min: UVec3, max: UVec3) {
+        let grouped = self.group.is_some();
+        if !grouped {
+            self.begin_group();
+        }
+
+        self.clear_node(svo, min, max, UVec3::ZERO, 0);
+
+        if !grouped {
+            self.end_group();
+        }
+    }
+
+    /// Clears the part of `[min, max)` inside the node `depth` levels below the
+    /// root whose minimum corner is `node_min`.
+    fn clear_node(
+        &mut self,
+        svo: &mut SparseVoxelOctree,
+        min: UVec3,
+        max: UVec3,
+        node_min: UVec3,
+        depth: u32,
+    ) {
+        let size = 2_u32.pow(svo.depth() - depth);
+        let node_max = node_min + size;
+        if min.cmpge(node_max).any() || max.cmple(node_min).any() {
+            return;
+        }
+
+        if *svo.subtree_ref(node_min.x, node_min.y, node_min.z, depth) == Node::Leaf(None) {
+            return;
+        }
+        if min.cmple(node_min).all() && max.cmpge(node_max).all() {
+            self.insert(
+                svo,
+                node_min.x,
+                node_min.y,
+                node_min.z,
+                Node::Leaf(None),
+                depth,
+            );
+            return;
+        }
+
+        let child_size = size / 2;
+        for i in 0..8 {
+            let offset = UVec3::new(i & 1, (i >> 1) & 1, (i >> 2) & 1);
+            self.clear_node(svo, min, max, node_min + offset * child_size, depth + 1);
+        }
+    }
+
+    /// Reverts the most recent step. Returns `false` if there was nothing to undo.
+    pub fn undo(&mut self, svo: &mut SparseVoxelOctree) -> bool {
+        self.end_group();
+
+        let Some(step) = self.undo.pop_back() else {
+            return false;
+        };
+        for change in step.changes.iter().rev() {
+            let p = change.position;
+            match change.grown_from {
+                Some(depth) => svo.replace_root(change.before.clone(), depth),
+                None => svo.insert(p.x, p.y, p.z, change.before.clone(), change.depth),
+            }
+        }
+        self.redo.push(step);
+
+        true
+    }
+
+    /// Reapplies the most recently undone step. Returns `false` if there was
+    /// nothing to redo.
+    pub fn redo(&mut self, svo: &mut SparseVoxelOctree) -> bool {
+        self.end_group();
+
+        let Some(step) = self.redo.pop() else {
+            return false;
+        };
+        for change in &step.changes {
+            let p = change.position;
+            svo.insert(p.x, p.y, p.z, change.after.clone(), change.depth);
+        }
+        self.undo.push_back(step);
+
+        true
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+            || self
+                .group
+                .as_ref()
+                .map_or(false, |step| !step.changes.is_empty())
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    fn record(&mut self, change: Change) {
+        match &mut self.group {
+            Some(step) => step.push(change),
+            None => {
+                let mut step = Step::default();
+                step.push(change);
+                self.commit(step);
+            }
+        }
+    }
+
+    /// Adds a finished step to the undo stack. A new edit invalidates whatever
+    /// was undone before it.
+    fn commit(&mut self, step: Step) {
+        if step.changes.is_empty() {
+            return;
+        }
+
+        self.bytes -= self.redo.drain(..).map(|step| step.bytes).sum::<usize>();
+        self.bytes += step.bytes;
+        self.undo.push_back(step);
+
+        while self.bytes > self.budget && self.undo.len() > 1 {
+            let oldest = self.undo.pop_front().unwrap();
+            self.bytes -= oldest.bytes;
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use shared::Voxel;
+
+    use super::*;
+
+    const DEPTH: u32 = 4;
+
+    fn voxel(material: u32) -> Node {
+        Node::Leaf(Some(Voxel { material }))
+    }
+
+    /// Applies a random insert, remove, clear or brush-like group of inserts.
+    fn random_edit(rng: &mut StdRng, history: &mut History, svo: &mut SparseVoxelOctree) {
+        let [x, y, z] = [(); 3].map(|_| rng.gen_range(0..1 << DEPTH));
+        match rng.gen_range(0..4) {
+            0 => history.remove(svo, x, y, z),
+            1 => {
+                let max = UVec3::new(x, y, z)
+                    + UVec3::new(
+                        rng.gen_range(1..8),
+                        rng.gen_range(1..8),
+                        rng.gen_range(1..8),
+                    );
+                history.clear_box(svo, UVec3::new(x, y, z), max);
+            }
+            2 => {
+                history.begin_group();
+                for i in 0..rng.gen_range(1..10) {
+                    history.insert(
+                        svo,
+                        (x + i) % (1 << DEPTH),
+                        y,
+                        z,
+                        voxel(rng.gen_range(0..3)),
+                        DEPTH,
+                    );
+                }
+                history.end_group();
+            }
+            _ => history.insert(
+                svo,
+                x,
+                y,
+                z,
+                voxel(rng.gen_range(0..3)),
+                rng.gen_range(1..=DEPTH),
+            ),
+        }
+    }
+
+    #[test]
+    fn undoing_every_edit_restores_the_original_tree() {
+        let mut rng = StdRng::seed_from_u64(15);
+
+        for mut svo in [
+            SparseVoxelOctree::new(DEPTH),
+            SparseVoxelOctree::empty(DEPTH),
+        ] {
+            let original = svo.root().clone();
+            let mut history = History::new();
+
+            for _ in 0..200 {
+                random_edit(&mut rng, &mut history, &mut svo);
+            }
+            let edited = svo.root().clone();
+
+            while history.undo(&mut svo) {}
+            assert!(*svo.root() == original);
+            assert!(!history.can_undo());
+
+            while history.redo(&mut svo) {}
+            assert!(*svo.root() == edited);
+        }
+    }
+
+    #[test]
+    fn groups_undo_as_one_step() {
+        let mut svo = SparseVoxelOctree::empty(DEPTH);
+        let mut history = History::new();
+
+        history.insert(&mut svo, 0, 0, 0, voxel(1), DEPTH);
+        history.begin_group();
+        for x in 1..6 {
+            history.insert(&mut svo, x, 0, 0, voxel(2), DEPTH);
+        }
+        history.end_group();
+
+        assert!(history.undo(&mut svo));
+        assert!(svo.get(0, 0, 0).is_some());
+        assert!((1..6).all(|x| svo.get(x, 0, 0).is_none()));
+
+        assert!(history.redo(&mut svo));
+        assert!((1..6).all(|x| svo.get(x, 0, 0).is_some()));
+    }
+
+    #[test]
+    fn undoing_an_edit_that_deepened_the_tree_restores_its_depth() {
+        let mut svo = SparseVoxelOctree::new(DEPTH);
+        let original = svo.root().clone();
+        let mut history = History::new();
+
+        history.begin_group();
+        history.insert(&mut svo, 3, 17, 5, voxel(7), DEPTH + 1);
+        history.insert(&mut svo, 30, 2, 2, voxel(8), DEPTH + 1);
+        history.end_group();
+        let edited = svo.root().clone();
+        assert_eq!(svo.depth(), DEPTH + 1);
+
+        assert!(history.undo(&mut svo));
+        assert_eq!(svo.depth(), DEPTH);
+        assert!(*svo.root() == original);
+
+        assert!(history.redo(&mut svo));
+        assert_eq!(svo.depth(), DEPTH + 1);
+        assert!(*svo.root() == edited);
+    }
+
+    #[test]
+    fn new_edits_discard_the_redo_stack() {
+        let mut svo = SparseVoxelOctree::empty(DEPTH);
+        let mut history = History::new();
+
+        history.insert(&mut svo, 1, 1, 1, voxel(1), DEPTH);
+        history.undo(&mut svo);
+        history.insert(&mut svo, 2, 2, 2, voxel(1), DEPTH);
+
+        assert!(!history.redo(&mut svo));
+        assert!(svo.get(1, 1, 1).is_none());
+    }
+
+    #[test]
+    fn the_budget_forgets_the_oldest_steps() {
+        let mut svo = SparseVoxelOctree::new(DEPTH);
+        // Clearing the whole gradient cube copies it, far more than the budget.
+        let mut history = History::with_budget(1024);
+
+        history.remove(&mut svo, 0, 0, 0);
+        history.clear_box(&mut svo, UVec3::ZERO, UVec3::splat(1 << DEPTH));
+        history.insert(&mut svo, 3, 3, 3, voxel(0), DEPTH);
+
+        assert!(history.undo(&mut svo));
+        assert!(svo.get(3, 3, 3).is_none());
+        assert!(!history.undo(&mut svo));
+        assert!(svo.get(5, 5, 5).is_none());
+    }
+
+    #[test]
+    fn clear_box_copies_only_occupied_subtrees() {
+        let mut svo = SparseVoxelOctree::empty(DEPTH);
+        svo.insert(9, 9, 9, voxel(4), DEPTH);
+        let mut history = History::new();
+
+        history.clear_box(&mut svo, UVec3::ZERO, UVec3::splat(12));
+
+        // Only the 4-voxel cube at (8, 8, 8) is both inside the box and occupied.
+        let changes = &history.undo[0].changes;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            (changes[0].position, changes[0].depth),
+            (UVec3::splat(8), 2)
+        );
+        history.undo(&mut svo);
+        assert_eq!(svo.get(9, 9, 9), Some(&Voxel { material: 4 }));
+    }
+}
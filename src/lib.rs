@@ -0,0 +1,17 @@
+pub mod app;
+pub mod camera;
+pub mod chunk;
+pub mod cpu;
+pub mod editor;
+pub mod gpu_octree;
+pub mod headless;
+pub mod history;
+pub mod image;
+pub mod palette;
+pub mod query;
+pub mod renderer;
+pub mod scene;
+pub mod sdf;
+pub mod svo;
+pub mod terrain;
+pub mod vox;
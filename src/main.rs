@@ -1,35 +1,161 @@
-use app::State;
-use winit::{window::Window, event_loop::{EventLoop, ControlFlow}, event::{Event, WindowEvent}, dpi::PhysicalSize};
+use voxel_tracer::{
+    app::State,
+    chunk::{self, ChunkManager},
+    gpu_octree,
+    headless::{self, RenderOptions},
+    scene,
+    svo::SparseVoxelOctree,
+    terrain::{self, TerrainOptions},
+};
+use winit::{
+    dpi::PhysicalSize,
+    event::{Event, WindowEvent},
+    event_loop::{ControlFlow, EventLoop},
+    window::Window,
+};
 
-mod svo;
-mod app;
+const TREE_DEPTH: u32 = 3;
+/// Chunks streamed in each direction from the camera's chunk.
+const CHUNK_RADIUS: u32 = 4;
 
 fn main() {
     env_logger::init();
-    pollster::block_on(run());
+
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("render") {
+        args.next();
+        let options = RenderOptions::parse(args).unwrap_or_else(|err| {
+            eprintln!("{err}\n{}", RenderOptions::USAGE);
+            std::process::exit(2);
+        });
+
+        if let Err(err) = pollster::block_on(headless::run(&options)) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.peek().map(String::as_str) == Some("convert") {
+        args.next();
+        let (Some(input), Some(output)) = (args.next(), args.next()) else {
+            eprintln!("usage: voxel-tracer convert <scene.vox|scene file> <out scene file>");
+            std::process::exit(2);
+        };
+
+        // Packing as a DAG is slower than `pack` but the file loads just the same.
+        let result =
+            scene::load_octree(&input).and_then(|svo| Ok(scene::save(&svo.pack_dag(), &output)?));
+        if let Err(err) = result {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.peek().map(String::as_str) == Some("terrain") {
+        args.next();
+        let (Some(Ok(seed)), Some(Ok(depth)), Some(output)) = (args.next().map(|seed| seed.parse()), args.next().map(|depth| depth.parse()), args.next()) else {
+            eprintln!("usage: voxel-tracer terrain <seed> <depth> <out scene file>");
+            std::process::exit(2);
+        };
+
+        let start = std::time::Instant::now();
+        let svo = terrain::generate(&TerrainOptions {
+            seed,
+            depth,
+            ..TerrainOptions::default()
+        });
+        log::info!("generated terrain in {:.2?}", start.elapsed());
+
+        if let Err(err) = scene::save(&svo.pack_dag(), output) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.peek().map(String::as_str) == Some("split") {
+        args.next();
+        let (Some(input), Some(Ok(depth)), Some(output)) = (args.next(), args.next().map(|depth| depth.parse()), args.next()) else {
+            eprintln!("usage: voxel-tracer split <scene.vox|scene file> <chunk depth> <out dir>");
+            std::process::exit(2);
+        };
+
+        let result = scene::load_octree(&input).and_then(|svo| {
+            std::fs::create_dir_all(&output)?;
+            Ok(chunk::split(&svo, depth, &output)?)
+        });
+        match result {
+            Ok(written) => println!("wrote {written} chunks"),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    pollster::block_on(run(args.next()));
 }
 
-async fn run() {
+/// Reports a scene that cannot be opened the way the subcommands do.
+fn exit_with_error(message: String) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
+
+async fn run(scene: Option<String>) {
+    // A directory holds the chunks of a world to stream instead of one scene.
+    let chunked = scene
+        .as_ref()
+        .filter(|path| std::path::Path::new(path).is_dir())
+        .map(|dir| match chunk::chunk_depth(dir) {
+            Ok(Some(depth)) => (dir.clone(), depth),
+            Ok(None) => exit_with_error(format!("{dir}: no chunk files")),
+            Err(err) => exit_with_error(format!("{dir}: {err}")),
+        });
+    let svo = match (&chunked, scene) {
+        (Some((_, depth)), _) => SparseVoxelOctree::empty(*depth),
+        (None, Some(path)) => scene::load_octree(&path)
+            .unwrap_or_else(|err| exit_with_error(format!("{path}: {err}"))),
+        (None, None) => SparseVoxelOctree::new(TREE_DEPTH),
+    };
+    // Packs the whole scene twice, so only when asked for.
+    if chunked.is_none() && log::log_enabled!(log::Level::Debug) {
+        log::debug!(
+            "DAG packing would shrink the scene {:.1}x",
+            svo.dag_compression_ratio()
+        );
+    }
+
     let event_loop = EventLoop::new();
     let window = Window::new(&event_loop).unwrap();
     window.set_inner_size(PhysicalSize::new(800, 500));
 
-    let mut state = State::new(window).await;
+    let mut state = State::new(window, svo).await;
+    if let Some((dir, depth)) = chunked {
+        // Every resident chunk shares one buffer of each kind.
+        let budget = gpu_octree::max_storage_buffer_size(&state.renderer.device) as usize;
+        let chunks = ChunkManager::new(&dir, depth, 1.0, CHUNK_RADIUS, budget)
+            .unwrap_or_else(|err| exit_with_error(format!("{dir}: {err}")));
+        state.stream_chunks(chunks);
+    }
 
     event_loop.run(move |event, _, control_flow| match event {
         Event::WindowEvent {
             ref event,
             window_id,
-        } if window_id == state.window.id() => if !state.input(event) { // UPDATED!
-            match event {
-                WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-                WindowEvent::Resized(physical_size) => {
-                    state.resize(*physical_size);
-                }
-                WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
-                    state.resize(**new_inner_size);
+        } if window_id == state.window.id() => {
+            if !state.input(event) {
+                match event {
+                    WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                    WindowEvent::Resized(physical_size) => {
+                        state.resize(*physical_size);
+                    }
+                    WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                        state.resize(**new_inner_size);
+                    }
+                    _ => {}
                 }
-                _ => {}
             }
         }
         Event::RedrawRequested(window_id) if window_id == state.window.id() => {
@@ -39,7 +165,7 @@ async fn run() {
                     Ok(_) => {}
                     Err(wgpu::SurfaceError::Lost) => state.resize(state.size),
                     Err(wgpu::SurfaceError::OutOfMemory) => *control_flow = ControlFlow::Exit,
-                    Err(e) => eprintln!("{:?}", e)
+                    Err(e) => eprintln!("{:?}", e),
                 }
             });
         }
@@ -0,0 +1,123 @@
+//! Materials shared between voxels. Leaves store an index into a
+//! [`MaterialPalette`] instead of a full [`Material`], so editing one palette
+//! entry recolors every voxel that uses it.
+
+use shared::Material;
+
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct MaterialPalette {
+    materials: Vec<Material>,
+}
+
+impl MaterialPalette {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `material` and returns its index. Identical materials added twice
+    /// get separate entries; use [`Self::find`] first to share one.
+    pub fn add(&mut self, material: Material) -> u32 {
+        self.materials.push(material);
+        self.materials.len() as u32 - 1
+    }
+
+    /// Index of the first entry equal to `material`.
+    pub fn find(&self, material: &Material) -> Option<u32> {
+        self.materials
+            .iter()
+            .position(|entry| entry == material)
+            .map(|index| index as u32)
+    }
+
+    pub fn get(&self, index: u32) -> Option<&Material> {
+        self.materials.get(index as usize)
+    }
+
+    /// Replaces the material at `index`, which must already exist.
+    pub fn set(&mut self, index: u32, material: Material) {
+        self.materials[index as usize] = material;
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+
+    /// Every entry in index order, laid out as the shader's material buffer.
+    pub fn materials(&self) -> &[Material] {
+        &self.materials
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Vec3};
+    use shader::Ray;
+    use shared::{ShaderConstants, Voxel};
+
+    use super::*;
+    use crate::svo::{Node, SparseVoxelOctree};
+
+    fn material(albedo: [f32; 3]) -> Material {
+        Material {
+            albedo,
+            roughness: 1.0,
+            emission: 0.0,
+        }
+    }
+
+    #[test]
+    fn add_find_and_set_entries() {
+        let mut palette = MaterialPalette::new();
+        let red = palette.add(material([1.0, 0.0, 0.0]));
+        let blue = palette.add(material([0.0, 0.0, 1.0]));
+
+        assert_eq!((red, blue), (0, 1));
+        assert_eq!(palette.find(&material([0.0, 0.0, 1.0])), Some(blue));
+        assert_eq!(palette.find(&material([0.0, 1.0, 0.0])), None);
+
+        palette.set(red, material([0.0, 1.0, 0.0]));
+        assert_eq!(palette.get(red), Some(&material([0.0, 1.0, 0.0])));
+        assert_eq!(palette.find(&material([0.0, 1.0, 0.0])), Some(red));
+        assert_eq!(palette.len(), 2);
+    }
+
+    #[test]
+    fn editing_an_entry_recolors_every_voxel_using_it() {
+        let mut svo = SparseVoxelOctree::empty(3);
+        let shared = svo.palette_mut().add(material([1.0, 0.0, 0.0]));
+        for x in 0..8 {
+            svo.insert(x, 0, 0, Node::Leaf(Some(Voxel { material: shared })), 3);
+        }
+        svo.palette_mut().set(shared, material([0.0, 0.0, 1.0]));
+
+        let packed = svo.pack();
+        let constants = ShaderConstants {
+            chunk_grid: 1,
+            tree_depth: packed.depth,
+            world_size: 8.0,
+            ..bytemuck::Zeroable::zeroed()
+        };
+        for x in 0..8 {
+            let mut ray = Ray {
+                origin: vec3(x as f32 + 0.5, 0.5, 10.0),
+                direction: Vec3::NEG_Z,
+                t: 0.0,
+            };
+            let hit = ray.traverse(
+                &constants,
+                &[packed.root],
+                &packed.nodes,
+                &packed.lods,
+                &packed.voxels,
+                &packed.materials,
+            );
+
+            assert_eq!(hit.material.albedo, [0.0, 0.0, 1.0]);
+        }
+        assert_eq!(packed.materials.len(), 1);
+    }
+}
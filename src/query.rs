@@ -0,0 +1,307 @@
+//! Region queries over a [`SparseVoxelOctree`]. A query walks the tree once,
+//! skipping every subtree that lies outside the region and reporting subtrees
+//! that lie fully inside it as single uniform [`Block`]s.
+
+use glam::{UVec3, Vec3};
+use shared::Voxel;
+
+use crate::svo::Node;
+
+/// How a cube of voxels relates to a [`Region`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Overlap {
+    Outside,
+    Partial,
+    Inside,
+}
+
+/// A set of voxels a query can be restricted to.
+pub trait Region {
+    /// Classifies the cube of `size` voxels per axis whose minimum corner is
+    /// `min`. A single voxel (`size == 1`) is never `Partial`.
+    fn overlap(&self, min: UVec3, size: u32) -> Overlap;
+}
+
+/// The voxels in the half-open box `[min, max)`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Aabb {
+    pub min: UVec3,
+    pub max: UVec3,
+}
+
+impl Aabb {
+    pub fn new(min: UVec3, max: UVec3) -> Self {
+        Self { min, max }
+    }
+}
+
+impl Region for Aabb {
+    fn overlap(&self, min: UVec3, size: u32) -> Overlap {
+        let max = min + size;
+        if self.min.cmpge(max).any() || self.max.cmple(min).any() {
+            Overlap::Outside
+        } else if self.min.cmple(min).all() && self.max.cmpge(max).all() {
+            Overlap::Inside
+        } else {
+            Overlap::Partial
+        }
+    }
+}
+
+/// The voxels whose centers lie within `radius` of `center`, in voxel units.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl Sphere {
+    pub fn new(center: Vec3, radius: f32) -> Self {
+        Self { center, radius }
+    }
+}
+
+impl Region for Sphere {
+    fn overlap(&self, min: UVec3, size: u32) -> Overlap {
+        // The box spanned by the centers of the cube's voxels.
+        let first = min.as_vec3() + 0.5;
+        let last = first + (size - 1) as f32;
+
+        let nearest = self.center.clamp(first, last);
+        let farthest = Vec3::select((self.center - first).cmpgt(last - self.center), first, last);
+        let radius_squared = self.radius * self.radius;
+
+        if nearest.distance_squared(self.center) > radius_squared {
+            Overlap::Outside
+        } else if farthest.distance_squared(self.center) <= radius_squared {
+            Overlap::Inside
+        } else {
+            Overlap::Partial
+        }
+    }
+}
+
+/// A cube of identical solid voxels, `size` voxels per axis from `min`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Block<'a> {
+    pub min: UVec3,
+    pub size: u32,
+    pub voxel: &'a Voxel,
+}
+
+impl<'a> Block<'a> {
+    /// Number of voxels in the block.
+    pub fn volume(&self) -> u64 {
+        (self.size as u64).pow(3)
+    }
+
+    /// Every voxel of the block with its position, in x, y, z order.
+    pub fn voxels(self) -> impl Iterator<Item = (UVec3, &'a Voxel)> {
+        let Self { min, size, voxel } = self;
+        (0..size).flat_map(move |z| {
+            (0..size).flat_map(move |y| (0..size).map(move |x| (min + UVec3::new(x, y, z), voxel)))
+        })
+    }
+}
+
+/// Solid blocks of a tree inside a region, returned by
+/// [`SparseVoxelOctree::blocks`](crate::svo::SparseVoxelOctree::blocks). Blocks
+/// come out in octant order and never overlap.
+pub struct Blocks<'a, R> {
+    region: R,
+    /// Nodes still to visit with their minimum corner and size. Leaves that only
+    /// partly overlap the region are pushed again as their eight octants.
+    stack: Vec<(&'a Node, UVec3, u32)>,
+}
+
+impl<'a, R: Region> Blocks<'a, R> {
+    pub(crate) fn new(root: &'a Node, size: u32, region: R) -> Self {
+        Self {
+            region,
+            stack: vec![(root, UVec3::ZERO, size)],
+        }
+    }
+
+    fn push_children(&mut self, node: &'a Node, min: UVec3, size: u32) {
+        let half = size / 2;
+        for i in (0..8).rev() {
+            let offset = UVec3::new(i & 1, (i >> 1) & 1, (i >> 2) & 1) * half;
+            let child = match node {
+                Node::Branch { children } => &children[i as usize],
+                Node::Leaf(_) => node,
+            };
+            self.stack.push((child, min + offset, half));
+        }
+    }
+}
+
+impl<'a, R: Region> Iterator for Blocks<'a, R> {
+    type Item = Block<'a>;
+
+    fn next(&mut self) -> Option<Block<'a>> {
+        while let Some((node, min, size)) = self.stack.pop() {
+            if matches!(node, Node::Leaf(None)) {
+                continue;
+            }
+
+            match (self.region.overlap(min, size), node) {
+                (Overlap::Outside, _) => {}
+                (Overlap::Inside, Node::Leaf(Some(voxel))) => {
+                    return Some(Block { min, size, voxel });
+                }
+                (overlap, _) => {
+                    debug_assert!(size > 1 || overlap == Overlap::Inside);
+                    self.push_children(node, min, size);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::svo::SparseVoxelOctree;
+
+    const DEPTH: u32 = 4;
+
+    fn voxel(material: u32) -> Node {
+        Node::Leaf(Some(Voxel { material }))
+    }
+
+    /// A tree mixing single voxels with uniform leaves of every size.
+    fn random_tree(seed: u64) -> SparseVoxelOctree {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut svo = SparseVoxelOctree::empty(DEPTH);
+        for _ in 0..40 {
+            let [x, y, z] = [(); 3].map(|_| rng.gen_range(0..1 << DEPTH));
+            let depth = rng.gen_range(1..=DEPTH);
+            if rng.gen_bool(0.2) {
+                svo.insert(x, y, z, Node::Leaf(None), depth);
+            } else {
+                svo.insert(x, y, z, voxel(rng.gen_range(0..3)), depth);
+            }
+        }
+        svo
+    }
+
+    /// Solid voxels of `svo` inside `region`, found one point query at a time.
+    fn brute_force(svo: &SparseVoxelOctree, region: &impl Region) -> Vec<(UVec3, Voxel)> {
+        let size = 1 << DEPTH;
+        let mut voxels = vec![];
+        for z in 0..size {
+            for y in 0..size {
+                for x in 0..size {
+                    let p = UVec3::new(x, y, z);
+                    if let (Some(voxel), Overlap::Inside) = (svo.get(x, y, z), region.overlap(p, 1))
+                    {
+                        voxels.push((p, *voxel));
+                    }
+                }
+            }
+        }
+        voxels
+    }
+
+    fn sorted_voxels<R: Region>(svo: &SparseVoxelOctree, region: R) -> Vec<(UVec3, Voxel)> {
+        let mut voxels: Vec<_> = svo
+            .voxels_in(region)
+            .map(|(p, voxel)| (p, *voxel))
+            .collect();
+        voxels.sort_by_key(|(p, _)| (p.z, p.y, p.x));
+        voxels
+    }
+
+    #[test]
+    fn box_and_sphere_queries_match_point_queries() {
+        let mut rng = StdRng::seed_from_u64(17);
+
+        for seed in 0..10 {
+            let svo = random_tree(seed);
+
+            let min = UVec3::from([(); 3].map(|_| rng.gen_range(0..16)));
+            let max = min + UVec3::from([(); 3].map(|_| rng.gen_range(0..12)));
+            let aabb = Aabb::new(min, max);
+            assert_eq!(sorted_voxels(&svo, aabb), brute_force(&svo, &aabb));
+            assert_eq!(svo.count_solid(aabb), brute_force(&svo, &aabb).len() as u64);
+
+            let center = vec3(rng.gen_range(-4.0..20.0), rng.gen_range(-4.0..20.0), 7.5);
+            let sphere = Sphere::new(center, rng.gen_range(0.0..10.0));
+            assert_eq!(sorted_voxels(&svo, sphere), brute_force(&svo, &sphere));
+            assert_eq!(
+                svo.is_region_empty(sphere),
+                brute_force(&svo, &sphere).is_empty()
+            );
+        }
+    }
+
+    #[test]
+    fn uniform_leaves_inside_the_region_are_one_block() {
+        let mut svo = SparseVoxelOctree::empty(DEPTH);
+        svo.insert(0, 0, 0, voxel(5), 1);
+
+        let blocks: Vec<_> = svo
+            .blocks(Aabb::new(UVec3::ZERO, UVec3::splat(16)))
+            .collect();
+        assert_eq!(
+            blocks,
+            [Block {
+                min: UVec3::ZERO,
+                size: 8,
+                voxel: &Voxel { material: 5 }
+            }]
+        );
+
+        // Clipping the leaf splits it only along the box's faces.
+        let clipped: Vec<_> = svo
+            .blocks(Aabb::new(UVec3::ZERO, UVec3::new(8, 8, 4)))
+            .collect();
+        assert_eq!(clipped.len(), 4);
+        assert!(clipped.iter().all(|block| block.size == 4));
+        assert_eq!(
+            svo.count_solid(Aabb::new(UVec3::ZERO, UVec3::new(8, 8, 4))),
+            256
+        );
+    }
+
+    #[test]
+    fn empty_regions_and_counts() {
+        let mut svo = SparseVoxelOctree::empty(DEPTH);
+        svo.insert(9, 9, 9, voxel(0), DEPTH);
+        let everything = Aabb::new(UVec3::ZERO, UVec3::splat(16));
+
+        assert!(svo.is_region_empty(Aabb::new(UVec3::ZERO, UVec3::splat(9))));
+        assert!(!svo.is_region_empty(Aabb::new(UVec3::ZERO, UVec3::splat(10))));
+        assert!(svo.is_region_empty(Sphere::new(vec3(9.5, 9.5, 7.0), 2.4)));
+        assert!(!svo.is_region_empty(Sphere::new(vec3(9.5, 9.5, 7.0), 2.5)));
+        assert_eq!(svo.count_solid(everything), 1);
+
+        let gradient = SparseVoxelOctree::new(3);
+        assert_eq!(gradient.count_solid(everything), 512);
+        assert_eq!(
+            gradient.count_solid(Sphere::new(Vec3::splat(4.0), 100.0)),
+            512
+        );
+    }
+
+    #[test]
+    fn blocks_too_large_to_index_in_u32_list_their_voxels() {
+        let block = Block {
+            min: UVec3::splat(4096),
+            size: 2048,
+            voxel: &Voxel { material: 1 },
+        };
+        assert_eq!(block.volume(), 1 << 33);
+
+        let mut voxels = block.voxels();
+        assert_eq!(voxels.next(), Some((UVec3::splat(4096), block.voxel)));
+        assert_eq!(voxels.next().unwrap().0, UVec3::new(4097, 4096, 4096));
+        let next_slice = voxels.nth(2048 * 2048 - 2).unwrap();
+        assert_eq!(next_slice.0, UVec3::new(4096, 4096, 4097));
+    }
+}
@@ -0,0 +1,466 @@
+use std::num::NonZeroU64;
+
+use bytemuck::Contiguous;
+use shared::{Material, PackedNode, ShaderConstants, Voxel};
+
+use crate::{
+    chunk::{ChunkTable, GpuChunks},
+    gpu_octree::GpuOctree,
+    svo::SparseVoxelOctree,
+};
+
+/// Format of the ping-pong textures holding the running average of every sample
+/// traced since the last reset.
+const ACCUMULATION_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba32Float;
+
+/// Everything needed to trace the octree into a color target, independent of
+/// whether that target is a window surface or an offscreen texture.
+pub struct Renderer {
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+
+    render_pipeline: wgpu::RenderPipeline,
+    pub shader_constants: ShaderConstants,
+
+    octree: GpuOctree,
+    /// Set while rendering a chunked world, whose buffers then replace the octree's.
+    chunks: Option<GpuChunks>,
+    bind_group: wgpu::BindGroup,
+
+    /// Bind group `i` reads accumulation texture `i` and writes the other one, so
+    /// consecutive frames alternate between them.
+    accumulation_bind_groups: [wgpu::BindGroup; 2],
+    sample_count: u32,
+}
+
+impl Renderer {
+    pub async fn request_device(
+        adapter: &wgpu::Adapter,
+    ) -> Result<(wgpu::Device, wgpu::Queue), wgpu::RequestDeviceError> {
+        // Take the largest storage buffers the adapter offers, since the octree
+        // and streamed chunks each live in one.
+        let supported = adapter.limits();
+        adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    features: wgpu::Features::PUSH_CONSTANTS
+                        | wgpu::Features::SPIRV_SHADER_PASSTHROUGH,
+                    limits: wgpu::Limits {
+                        max_push_constant_size: 128,
+                        max_storage_buffer_binding_size: supported.max_storage_buffer_binding_size,
+                        max_buffer_size: supported.max_buffer_size,
+                        ..Default::default()
+                    },
+                    label: None,
+                },
+                None, // Trace path
+            )
+            .await
+    }
+
+    pub fn new(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+        svo: &SparseVoxelOctree,
+    ) -> Self {
+        let shader = unsafe {
+            device.create_shader_module_spirv(&wgpu::include_spirv_raw!(env!("shader.spv")))
+        };
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: NonZeroU64::from_integer(32),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: NonZeroU64::new(std::mem::size_of::<Voxel>() as u64),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: NonZeroU64::new(std::mem::size_of::<Material>() as u64),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: NonZeroU64::new(std::mem::size_of::<PackedNode>() as u64),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: NonZeroU64::new(std::mem::size_of::<PackedNode>() as u64),
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("bind_group_layout"),
+        });
+
+        let accumulation_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::StorageTexture {
+                            access: wgpu::StorageTextureAccess::WriteOnly,
+                            format: ACCUMULATION_FORMAT,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some("accumulation_bind_group_layout"),
+            });
+
+        let render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Render Pipeline Layout"),
+                bind_group_layouts: &[&bind_group_layout, &accumulation_bind_group_layout],
+                push_constant_ranges: &[wgpu::PushConstantRange {
+                    stages: wgpu::ShaderStages::FRAGMENT,
+                    range: 0..std::mem::size_of::<ShaderConstants>() as u32,
+                }],
+            });
+
+        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Render Pipeline"),
+            layout: Some(&render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "main_vs",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "main_fs",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        });
+
+        let octree = GpuOctree::new(&device, svo);
+        let bind_group =
+            Self::create_octree_bind_group(&device, &render_pipeline, octree.buffers());
+        let accumulation_bind_groups =
+            Self::create_accumulation_bind_groups(&device, &render_pipeline, width, height);
+
+        let shader_constants = Self::initial_constants(svo.depth(), width, height);
+
+        Self {
+            device,
+            queue,
+
+            render_pipeline,
+            shader_constants,
+
+            octree,
+            chunks: None,
+            bind_group,
+
+            accumulation_bind_groups,
+            sample_count: 0,
+        }
+    }
+
+    /// Constants for a `width` x `height` frame of a single tree of the given depth,
+    /// with one world unit per voxel and the camera left for the caller to write.
+    pub fn initial_constants(depth: u32, width: u32, height: u32) -> ShaderConstants {
+        ShaderConstants {
+            width,
+            height,
+            time: 0.0,
+            sample_count: 0,
+            chunk_grid: 1,
+            tree_depth: depth,
+            world_origin: [0.0; 3],
+            world_size: (1 << depth) as f32,
+            camera_position: [0.0; 3],
+            camera_yaw: 0.0,
+            camera_pitch: 0.0,
+            camera_fov: 0.0,
+            lod_bias: 1.0,
+        }
+    }
+
+    /// Binds the node, voxel, material, chunk and level-of-detail buffers, in that
+    /// order.
+    fn create_octree_bind_group(
+        device: &wgpu::Device,
+        render_pipeline: &wgpu::RenderPipeline,
+        buffers: [&wgpu::Buffer; 5],
+    ) -> wgpu::BindGroup {
+        let entries = buffers.map(|buffer| buffer.as_entire_binding());
+        let [nodes, voxels, materials, chunks, lods] = entries;
+
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Bind Group"),
+            layout: &render_pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: nodes,
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: voxels,
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: materials,
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: chunks,
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: lods,
+                },
+            ],
+        })
+    }
+
+    /// Rebuilds the bind group from whichever buffers are being rendered.
+    fn rebind(&mut self) {
+        let buffers = match &self.chunks {
+            Some(chunks) => chunks.buffers(),
+            None => self.octree.buffers(),
+        };
+        self.bind_group =
+            Self::create_octree_bind_group(&self.device, &self.render_pipeline, buffers);
+    }
+
+    fn create_accumulation_bind_groups(
+        device: &wgpu::Device,
+        render_pipeline: &wgpu::RenderPipeline,
+        width: u32,
+        height: u32,
+    ) -> [wgpu::BindGroup; 2] {
+        let views = [0, 1].map(|_| {
+            device
+                .create_texture(&wgpu::TextureDescriptor {
+                    label: Some("accumulation_texture"),
+                    size: wgpu::Extent3d {
+                        width,
+                        height,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: ACCUMULATION_FORMAT,
+                    usage: wgpu::TextureUsages::TEXTURE_BINDING
+                        | wgpu::TextureUsages::STORAGE_BINDING,
+                    view_formats: &[],
+                })
+                .create_view(&wgpu::TextureViewDescriptor::default())
+        });
+
+        [0, 1].map(|i| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Accumulation Bind Group"),
+                layout: &render_pipeline.get_bind_group_layout(1),
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&views[i]),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&views[1 - i]),
+                    },
+                ],
+            })
+        })
+    }
+
+    /// Number of samples averaged into the accumulation texture so far.
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    /// Discards the accumulated samples; the next frame starts a new average. Call
+    /// this whenever something changes the image other than noise, such as the camera.
+    pub fn reset_accumulation(&mut self) {
+        self.sample_count = 0;
+    }
+
+    /// Replaces the rendered octree, switching back from a chunked world if one was
+    /// set. The world size is rescaled so the new tree keeps the current voxel
+    /// size, whatever its depth.
+    pub fn set_octree(&mut self, svo: &SparseVoxelOctree) {
+        self.octree = GpuOctree::new(&self.device, svo);
+        self.chunks = None;
+        self.rebind();
+        self.set_tree_depth(svo.depth());
+        self.reset_accumulation();
+    }
+
+    /// Uploads the edits made to `svo` since the last call, which must be the tree
+    /// passed to [`Self::new`] or [`Self::set_octree`]. Only changed nodes, voxels
+    /// and palette entries are written, so recoloring a material is one small write.
+    /// Does nothing while a chunked world is rendered instead.
+    pub fn update_octree(&mut self, svo: &mut SparseVoxelOctree) {
+        if self.chunks.is_some() {
+            return;
+        }
+
+        let (changed, reallocated) = self.octree.update(&self.device, &self.queue, svo);
+
+        if reallocated {
+            self.rebind();
+        }
+        if changed {
+            self.set_tree_depth(svo.depth());
+            self.reset_accumulation();
+        }
+    }
+
+    /// Renders the resident chunks of a chunked world in place of the octree. Call
+    /// it again whenever [`crate::chunk::ChunkManager::update`] reports a change.
+    pub fn set_chunks(&mut self, table: &ChunkTable) {
+        let reallocated = match &mut self.chunks {
+            Some(chunks) => chunks.update(&self.device, &self.queue, table),
+            None => {
+                self.chunks = Some(GpuChunks::new(&self.device, table));
+                true
+            }
+        };
+
+        if reallocated {
+            self.rebind();
+        }
+        table.write_constants(&mut self.shader_constants);
+        self.reset_accumulation();
+    }
+
+    fn set_tree_depth(&mut self, depth: u32) {
+        let voxel_size = self.shader_constants.voxel_size();
+
+        self.shader_constants.chunk_grid = 1;
+        self.shader_constants.tree_depth = depth;
+        self.shader_constants.world_size = voxel_size * (1 << depth) as f32;
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.shader_constants.width = width;
+        self.shader_constants.height = height;
+
+        self.accumulation_bind_groups = Self::create_accumulation_bind_groups(
+            &self.device,
+            &self.render_pipeline,
+            width,
+            height,
+        );
+        self.reset_accumulation();
+    }
+
+    /// Traces one more sample per pixel, blends it into the running average and
+    /// writes the average to `view`.
+    pub fn render(&mut self, view: &wgpu::TextureView) {
+        self.shader_constants.sample_count = self.sample_count;
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Render Encoder"),
+            });
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color {
+                            r: 0.01,
+                            g: 0.01,
+                            b: 0.01,
+                            a: 1.0,
+                        }),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+
+            render_pass.set_pipeline(&self.render_pipeline);
+            render_pass.set_push_constants(
+                wgpu::ShaderStages::FRAGMENT,
+                0,
+                bytemuck::bytes_of(&self.shader_constants),
+            );
+            render_pass.set_bind_group(0, &self.bind_group, &[]);
+            render_pass.set_bind_group(
+                1,
+                &self.accumulation_bind_groups[(self.sample_count % 2) as usize],
+                &[],
+            );
+            render_pass.draw(0..3, 0..1);
+        }
+
+        self.queue.submit(std::iter::once(encoder.finish()));
+        self.sample_count += 1;
+    }
+}
@@ -0,0 +1,505 @@
+//! Binary scene files holding a [`PackedSparseVoxelOctree`] exactly as the
+//! shader reads it, so loading skips rebuilding the tree.
+//!
+//! All fields are little-endian:
+//!
+//! | offset | size          | contents                                           |
+//! |--------|---------------|----------------------------------------------------|
+//! | 0      | 8             | magic `VXSCENE\0`                                  |
+//! | 8      | 4             | format version, currently 1                        |
+//! | 12     | 4             | tree depth                                         |
+//! | 16     | 4             | root [`PackedNode`]                                |
+//! | 20     | 4             | node count `n`                                     |
+//! | 24     | 4             | voxel count `v`                                    |
+//! | 28     | 4             | material count `m`, zero when there is no palette  |
+//! | 32     | 4             | CRC-32 of bytes 0 to 32 followed by the payload    |
+//! | 36     | 32 `n`        | nodes, eight [`PackedNode`]s each                  |
+//! |        | 4 `v`         | voxels                                             |
+//! |        | 20 `m`        | materials: albedo, roughness, emission as `f32`    |
+//!
+//! Children are stored before their parents, as [`SparseVoxelOctree::pack`] and
+//! [`SparseVoxelOctree::pack_dag`] lay them out. Loading relies on it to reject
+//! cycles, and to rebuild the level-of-detail entries of
+//! [`PackedSparseVoxelOctree::lods`] in one pass instead of storing them. Every
+//! array starts at a multiple of four bytes, so a memory-mapped file is read in
+//! place by [`MappedScene`].
+
+use std::{error::Error, fmt, fs, io, mem::size_of, ops::Range, path::Path};
+
+use bytemuck::Pod;
+use memmap2::Mmap;
+use shared::{Material, PackedNode, Voxel};
+
+use crate::{
+    svo::{self, PackedSparseVoxelOctree, SparseVoxelOctree},
+    vox,
+};
+
+const MAGIC: &[u8; 8] = b"VXSCENE\0";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 36;
+/// Offset of the checksum, which covers everything but itself.
+const CHECKSUM: Range<usize> = 32..36;
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    ChecksumMismatch,
+    /// The checksum matched but the contents do not form a valid tree.
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Io(err) => write!(f, "failed to read scene file: {err}"),
+            SceneError::InvalidMagic => write!(f, "not a scene file"),
+            SceneError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "scene file version {version} is not supported, expected {VERSION}"
+                )
+            }
+            SceneError::Truncated => write!(f, "unexpected end of scene data"),
+            SceneError::ChecksumMismatch => write!(f, "scene file checksum does not match"),
+            SceneError::Corrupt(what) => write!(f, "corrupt scene file: {what}"),
+        }
+    }
+}
+
+impl Error for SceneError {}
+
+impl From<io::Error> for SceneError {
+    fn from(err: io::Error) -> Self {
+        SceneError::Io(err)
+    }
+}
+
+pub fn save(packed: &PackedSparseVoxelOctree, path: impl AsRef<Path>) -> Result<(), SceneError> {
+    fs::write(path, encode(packed))?;
+    Ok(())
+}
+
+/// Reads a whole scene file into memory. Use [`MappedScene`] to upload the
+/// arrays without copying them first.
+pub fn load(path: impl AsRef<Path>) -> Result<PackedSparseVoxelOctree, SceneError> {
+    decode(&fs::read(path)?)
+}
+
+/// Loads a tree from either a .vox file or a scene file, told apart by the
+/// `.vox` extension.
+pub fn load_octree(path: impl AsRef<Path>) -> Result<SparseVoxelOctree, Box<dyn Error>> {
+    let path = path.as_ref();
+    if path.extension().and_then(|extension| extension.to_str()) == Some("vox") {
+        Ok(vox::load(path)?)
+    } else {
+        Ok(load(path)?.unpack())
+    }
+}
+
+pub fn encode(packed: &PackedSparseVoxelOctree) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + packed.size_in_bytes());
+    bytes.extend_from_slice(MAGIC);
+    for field in [
+        VERSION,
+        packed.depth,
+        packed.root.0,
+        packed.nodes.len() as u32,
+        packed.voxels.len() as u32,
+        packed.materials.len() as u32,
+        0,
+    ] {
+        bytes.extend_from_slice(&field.to_le_bytes());
+    }
+    bytes.extend_from_slice(bytemuck::cast_slice(&packed.nodes));
+    bytes.extend_from_slice(bytemuck::cast_slice(&packed.voxels));
+    bytes.extend_from_slice(bytemuck::cast_slice(&packed.materials));
+
+    let checksum = checksum(&bytes);
+    bytes[CHECKSUM].copy_from_slice(&checksum.to_le_bytes());
+    bytes
+}
+
+/// Upper bound on the [`PackedSparseVoxelOctree::size_in_bytes`] of a scene file
+/// of `file_len` bytes, for reserving memory before reading it. Decoding adds a
+/// level-of-detail entry of 4 bytes for every 32-byte node in the payload.
+pub fn packed_size_bound(file_len: u64) -> usize {
+    let payload = file_len.saturating_sub(HEADER_SIZE as u64) as usize;
+    payload + payload / 8
+}
+
+pub fn decode(bytes: &[u8]) -> Result<PackedSparseVoxelOctree, SceneError> {
+    let layout = Layout::parse(bytes)?;
+
+    let mut packed = PackedSparseVoxelOctree {
+        root: layout.root,
+        depth: layout.depth,
+        nodes: read_array(&bytes[layout.nodes.clone()]),
+        lods: vec![],
+        voxels: read_array(&bytes[layout.voxels.clone()]),
+        materials: read_array(&bytes[layout.materials.clone()]),
+    };
+    layout.validate(&packed.nodes, &packed.voxels)?;
+    packed.lods = svo::compute_lods(&packed.nodes, &packed.voxels);
+
+    Ok(packed)
+}
+
+/// A scene file mapped into memory, with its arrays borrowed straight from the
+/// mapping, for reading the header or arrays of large files without copying
+/// them. The viewer loads scenes through [`load_octree`] instead, since editing
+/// needs the unpacked tree.
+pub struct MappedScene {
+    map: Mmap,
+    layout: Layout,
+}
+
+impl MappedScene {
+    /// Maps and validates the file. The file must not be modified while mapped.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, SceneError> {
+        let file = fs::File::open(path)?;
+        // SAFETY: the mapping is read-only and the contract above forbids
+        // writers, so the bytes stay fixed for the lifetime of `map`.
+        let map = unsafe { Mmap::map(&file)? };
+
+        let layout = Layout::parse(&map)?;
+        let scene = Self { map, layout };
+        scene.layout.validate(scene.nodes(), scene.voxels())?;
+
+        Ok(scene)
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.layout.depth
+    }
+
+    pub fn root(&self) -> PackedNode {
+        self.layout.root
+    }
+
+    pub fn nodes(&self) -> &[[PackedNode; 8]] {
+        bytemuck::cast_slice(&self.map[self.layout.nodes.clone()])
+    }
+
+    pub fn voxels(&self) -> &[Voxel] {
+        bytemuck::cast_slice(&self.map[self.layout.voxels.clone()])
+    }
+
+    pub fn materials(&self) -> &[Material] {
+        bytemuck::cast_slice(&self.map[self.layout.materials.clone()])
+    }
+
+    /// Level-of-detail entries of the nodes. They are not stored, so this
+    /// computes them.
+    pub fn lods(&self) -> Vec<PackedNode> {
+        svo::compute_lods(self.nodes(), self.voxels())
+    }
+
+    /// Copies the scene out of the mapping.
+    pub fn to_packed(&self) -> PackedSparseVoxelOctree {
+        PackedSparseVoxelOctree {
+            root: self.root(),
+            depth: self.depth(),
+            nodes: self.nodes().to_vec(),
+            lods: self.lods(),
+            voxels: self.voxels().to_vec(),
+            materials: self.materials().to_vec(),
+        }
+    }
+}
+
+/// Header fields and the byte ranges of the arrays they describe.
+struct Layout {
+    depth: u32,
+    root: PackedNode,
+    nodes: Range<usize>,
+    voxels: Range<usize>,
+    materials: Range<usize>,
+}
+
+impl Layout {
+    /// Checks the header, length and checksum of `bytes`.
+    fn parse(bytes: &[u8]) -> Result<Self, SceneError> {
+        let magic = &bytes[..bytes.len().min(MAGIC.len())];
+        if !MAGIC.starts_with(magic) {
+            return Err(SceneError::InvalidMagic);
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(SceneError::Truncated);
+        }
+
+        let field = |i: usize| {
+            let offset = MAGIC.len() + 4 * i;
+            u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+        };
+        let version = field(0);
+        if version != VERSION {
+            return Err(SceneError::UnsupportedVersion(version));
+        }
+
+        let mut end = HEADER_SIZE;
+        let mut array = |count: u32, size: usize| -> Result<Range<usize>, SceneError> {
+            let start = end;
+            end = (count as usize)
+                .checked_mul(size)
+                .and_then(|len| start.checked_add(len))
+                .ok_or(SceneError::Truncated)?;
+            Ok(start..end)
+        };
+        let layout = Layout {
+            depth: field(1),
+            root: PackedNode(field(2)),
+            nodes: array(field(3), size_of::<[PackedNode; 8]>())?,
+            voxels: array(field(4), size_of::<Voxel>())?,
+            materials: array(field(5), size_of::<Material>())?,
+        };
+
+        if bytes.len() < end {
+            return Err(SceneError::Truncated);
+        }
+        if bytes.len() > end {
+            return Err(SceneError::Corrupt("trailing data"));
+        }
+        if bytes[CHECKSUM] != checksum(bytes).to_le_bytes() {
+            return Err(SceneError::ChecksumMismatch);
+        }
+
+        Ok(layout)
+    }
+
+    /// Checks that the depth is one the octree supports, that every index points
+    /// into its array, that children come before their parents and that the tree
+    /// is no deeper than its header claims.
+    fn validate(&self, nodes: &[[PackedNode; 8]], voxels: &[Voxel]) -> Result<(), SceneError> {
+        if self.depth > svo::MAX_DEPTH {
+            return Err(SceneError::Corrupt("depth out of range"));
+        }
+
+        let materials = self.materials.len() / size_of::<Material>();
+        if materials > 0
+            && voxels
+                .iter()
+                .any(|voxel| voxel.material as usize >= materials)
+        {
+            return Err(SceneError::Corrupt("material index out of range"));
+        }
+
+        // Height of every branch, filled in storage order so children are known
+        // before their parents.
+        let mut heights = Vec::with_capacity(nodes.len());
+        let height = |node: PackedNode, parent: usize, heights: &[u32]| {
+            if node.is_empty() {
+                Ok(0)
+            } else if node.is_leaf() {
+                match ((node.0 & !(1 << 31)) as usize) < voxels.len() {
+                    true => Ok(0),
+                    false => Err(SceneError::Corrupt("voxel index out of range")),
+                }
+            } else {
+                match heights
+                    .get(node.0 as usize)
+                    .filter(|_| (node.0 as usize) < parent)
+                {
+                    Some(height) => Ok(height + 1),
+                    None => Err(SceneError::Corrupt("child stored after its parent")),
+                }
+            }
+        };
+
+        for (i, children) in nodes.iter().enumerate() {
+            let mut branch = 1;
+            for child in children {
+                branch = branch.max(height(*child, i, &heights)? + 1);
+            }
+            heights.push(branch - 1);
+        }
+        if height(self.root, nodes.len(), &heights)? > self.depth {
+            return Err(SceneError::Corrupt("tree deeper than its depth"));
+        }
+
+        Ok(())
+    }
+}
+
+/// CRC-32 of a whole file, skipping the checksum field itself.
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&bytes[..CHECKSUM.start]);
+    hasher.update(&bytes[CHECKSUM.end..]);
+    hasher.finalize()
+}
+
+/// Copies an array out of bytes that need not be aligned for `T`.
+fn read_array<T: Pod>(bytes: &[u8]) -> Vec<T> {
+    bytes
+        .chunks_exact(size_of::<T>())
+        .map(bytemuck::pod_read_unaligned)
+        .collect()
+}
+
+/// A path in the system's temporary directory, unique to the test.
+#[cfg(test)]
+pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("voxel-tracer-{}-{name}", std::process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::UVec3;
+
+    use super::*;
+    use crate::svo::Node;
+
+    fn scene() -> SparseVoxelOctree {
+        let mut svo = SparseVoxelOctree::new(3);
+        svo.clear_box(UVec3::new(2, 0, 0), UVec3::new(8, 8, 4));
+        svo
+    }
+
+    fn assert_same(a: &PackedSparseVoxelOctree, b: &PackedSparseVoxelOctree) {
+        assert_eq!((a.root, a.depth), (b.root, b.depth));
+        assert_eq!(a.nodes, b.nodes);
+        assert_eq!(a.lods, b.lods);
+        assert_eq!(a.voxels, b.voxels);
+        assert_eq!(a.materials, b.materials);
+    }
+
+    /// Encodes `packed` with the header field after the version, numbered from
+    /// zero, replaced and the checksum fixed up to match.
+    fn encode_with_field(packed: &PackedSparseVoxelOctree, field: usize, value: u32) -> Vec<u8> {
+        let mut bytes = encode(packed);
+        let offset = MAGIC.len() + 4 * field;
+        bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        let checksum = checksum(&bytes);
+        bytes[CHECKSUM].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn round_trips_trees_and_dags() {
+        let svo = scene();
+
+        for packed in [svo.pack(), svo.pack_dag()] {
+            assert_same(&decode(&encode(&packed)).unwrap(), &packed);
+
+            let unpacked = decode(&encode(&packed)).unwrap().unpack();
+            assert!(unpacked.root() == svo.root());
+            assert_eq!(unpacked.palette(), svo.palette());
+        }
+
+        // Trees without a palette store no materials.
+        let mut bare = SparseVoxelOctree::empty(2);
+        bare.insert(1, 2, 3, Node::Leaf(Some(Voxel { material: 9 })), 2);
+        let packed = bare.pack();
+        assert_same(&decode(&encode(&packed)).unwrap(), &packed);
+    }
+
+    #[test]
+    fn maps_saved_files_in_place() {
+        let packed = scene().pack_dag();
+        let path = temp_path("mapped.scene");
+        save(&packed, &path).unwrap();
+
+        let mapped = MappedScene::open(&path).unwrap();
+        assert_eq!(mapped.nodes(), packed.nodes);
+        assert_eq!(mapped.voxels(), packed.voxels);
+        assert_eq!(mapped.materials(), packed.materials);
+        assert_same(&mapped.to_packed(), &load(&path).unwrap());
+
+        assert!(load_octree(&path).unwrap().root() == scene().root());
+        drop(mapped);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_scenes() {
+        assert!(matches!(
+            decode(b"VOX \x96\0\0\0"),
+            Err(SceneError::InvalidMagic)
+        ));
+        assert!(matches!(decode(b"VX"), Err(SceneError::Truncated)));
+        assert!(matches!(
+            MappedScene::open(temp_path("missing.scene")),
+            Err(SceneError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = encode(&scene().pack());
+        bytes[8..12].copy_from_slice(&2_u32.to_le_bytes());
+
+        assert!(matches!(
+            decode(&bytes),
+            Err(SceneError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = encode(&scene().pack());
+
+        for len in [0, 20, HEADER_SIZE, bytes.len() / 2, bytes.len() - 1] {
+            assert!(
+                matches!(decode(&bytes[..len]), Err(SceneError::Truncated)),
+                "{len} bytes"
+            );
+        }
+        // Counts large enough to overflow the file size.
+        let huge = encode_with_field(&scene().pack(), 3, u32::MAX);
+        assert!(matches!(decode(&huge), Err(SceneError::Truncated)));
+    }
+
+    #[test]
+    fn rejects_corrupted_bytes() {
+        let mut bytes = encode(&scene().pack());
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(matches!(decode(&bytes), Err(SceneError::ChecksumMismatch)));
+
+        let mut bytes = encode(&scene().pack());
+        bytes.push(0);
+        assert!(matches!(
+            decode(&bytes),
+            Err(SceneError::Corrupt("trailing data"))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_trees_with_valid_checksums() {
+        let valid = scene().pack();
+        let corrupt = |packed: &PackedSparseVoxelOctree| match decode(&encode(packed)) {
+            Err(SceneError::Corrupt(what)) => what,
+            other => panic!("expected a corrupt file, got {:?}", other.map(|_| ())),
+        };
+
+        let mut packed = scene().pack();
+        packed.nodes[0][0] = PackedNode(1 << 31 | valid.voxels.len() as u32);
+        assert_eq!(corrupt(&packed), "voxel index out of range");
+
+        // A node pointing at itself would send unpacking into a loop.
+        let mut packed = scene().pack();
+        packed.nodes[3][0] = PackedNode(3);
+        assert_eq!(corrupt(&packed), "child stored after its parent");
+
+        let mut packed = scene().pack();
+        packed.root = PackedNode(valid.nodes.len() as u32);
+        assert_eq!(corrupt(&packed), "child stored after its parent");
+
+        let mut packed = scene().pack();
+        packed.depth = 2;
+        assert_eq!(corrupt(&packed), "tree deeper than its depth");
+
+        // Deeper trees would overflow their size when rendered or streamed.
+        let mut packed = scene().pack();
+        packed.depth = 32;
+        assert_eq!(corrupt(&packed), "depth out of range");
+
+        let mut packed = scene().pack();
+        packed.voxels[0].material = valid.materials.len() as u32;
+        assert_eq!(corrupt(&packed), "material index out of range");
+    }
+}
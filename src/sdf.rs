@@ -0,0 +1,350 @@
+//! Signed distance fields for describing shapes by constructive solid geometry,
+//! and [`voxelize_sdf`] for turning them into trees.
+//!
+//! Distances are in voxel units, with voxel `(x, y, z)` spanning `[x, x + 1)` on
+//! each axis. Primitives are centered on the origin and placed with
+//! [`Sdf::translate`], [`Sdf::rotate`] and [`Sdf::scale`].
+
+use glam::{Quat, UVec3, Vec3, Vec3Swizzles};
+use shared::Voxel;
+
+use crate::svo::{Fill, SparseVoxelOctree};
+
+/// A shape given by the signed distance to its surface, negative inside.
+///
+/// A field may underestimate the distance but must never overestimate it: moving
+/// a point by `d` changes its distance by at most `d`. Every shape and
+/// combinator here keeps that bound, and [`voxelize_sdf`] relies on it to skip
+/// whole cubes.
+pub trait Sdf: Sync {
+    /// Signed distance from `p` to the surface.
+    fn distance(&self, p: Vec3) -> f32;
+
+    /// Points in either shape.
+    fn union<B: Sdf>(self, other: B) -> Union<Self, B>
+    where
+        Self: Sized,
+    {
+        Union(self, other)
+    }
+
+    /// Points in this shape but not in `other`.
+    fn subtract<B: Sdf>(self, other: B) -> Subtract<Self, B>
+    where
+        Self: Sized,
+    {
+        Subtract(self, other)
+    }
+
+    /// Points in both shapes.
+    fn intersect<B: Sdf>(self, other: B) -> Intersect<Self, B>
+    where
+        Self: Sized,
+    {
+        Intersect(self, other)
+    }
+
+    /// [`Self::union`] with the seam filleted over about `k` voxels.
+    fn smooth_union<B: Sdf>(self, other: B, k: f32) -> SmoothUnion<Self, B>
+    where
+        Self: Sized,
+    {
+        SmoothUnion(self, other, k)
+    }
+
+    fn translate(self, offset: Vec3) -> Translate<Self>
+    where
+        Self: Sized,
+    {
+        Translate(self, offset)
+    }
+
+    /// Rotates the shape about the origin.
+    fn rotate(self, rotation: Quat) -> Rotate<Self>
+    where
+        Self: Sized,
+    {
+        Rotate(self, rotation.inverse())
+    }
+
+    /// Scales the shape uniformly about the origin.
+    fn scale(self, factor: f32) -> Scale<Self>
+    where
+        Self: Sized,
+    {
+        Scale(self, factor)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Sphere {
+    pub radius: f32,
+}
+
+impl Sdf for Sphere {
+    fn distance(&self, p: Vec3) -> f32 {
+        p.length() - self.radius
+    }
+}
+
+/// A box spanning `-half_extents..half_extents`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Cuboid {
+    pub half_extents: Vec3,
+}
+
+impl Sdf for Cuboid {
+    fn distance(&self, p: Vec3) -> f32 {
+        let q = p.abs() - self.half_extents;
+        q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+    }
+}
+
+/// The points within `radius` of the segment from `a` to `b`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Capsule {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub radius: f32,
+}
+
+impl Sdf for Capsule {
+    fn distance(&self, p: Vec3) -> f32 {
+        let (pa, ba) = (p - self.a, self.b - self.a);
+        let t = (pa.dot(ba) / ba.length_squared()).clamp(0.0, 1.0);
+        // A zero length segment is a sphere around `a`.
+        let t = if t.is_nan() { 0.0 } else { t };
+        (pa - ba * t).length() - self.radius
+    }
+}
+
+/// A ring around the y axis, `major_radius` from the axis to the center of its
+/// tube of radius `minor_radius`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Torus {
+    pub major_radius: f32,
+    pub minor_radius: f32,
+}
+
+impl Sdf for Torus {
+    fn distance(&self, p: Vec3) -> f32 {
+        let ring = p.xz().length() - self.major_radius;
+        glam::vec2(ring, p.y).length() - self.minor_radius
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Union<A, B>(A, B);
+
+impl<A: Sdf, B: Sdf> Sdf for Union<A, B> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.0.distance(p).min(self.1.distance(p))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Subtract<A, B>(A, B);
+
+impl<A: Sdf, B: Sdf> Sdf for Subtract<A, B> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.0.distance(p).max(-self.1.distance(p))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Intersect<A, B>(A, B);
+
+impl<A: Sdf, B: Sdf> Sdf for Intersect<A, B> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.0.distance(p).max(self.1.distance(p))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SmoothUnion<A, B>(A, B, f32);
+
+impl<A: Sdf, B: Sdf> Sdf for SmoothUnion<A, B> {
+    fn distance(&self, p: Vec3) -> f32 {
+        // Polynomial smooth minimum. Its slope in each distance is between 0 and
+        // 1 and the slopes sum to 1, so it keeps the distance bound.
+        let (a, b, k) = (self.0.distance(p), self.1.distance(p), self.2);
+        if k <= 0.0 {
+            return a.min(b);
+        }
+        let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+        b + (a - b) * h - k * h * (1.0 - h)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Translate<A>(A, Vec3);
+
+impl<A: Sdf> Sdf for Translate<A> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.0.distance(p - self.1)
+    }
+}
+
+/// Holds the inverse rotation, which takes points back into the shape's frame.
+#[derive(Clone, Copy, Debug)]
+pub struct Rotate<A>(A, Quat);
+
+impl<A: Sdf> Sdf for Rotate<A> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.0.distance(self.1 * p)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Scale<A>(A, f32);
+
+impl<A: Sdf> Sdf for Scale<A> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.0.distance(p / self.1) * self.1
+    }
+}
+
+/// Builds a tree of the given depth holding every voxel whose center has a
+/// distance of at most zero, made of the material `material` gives for its
+/// center. Cubes whose center is further from the surface than any of their
+/// voxel centers are filled or left empty without visiting their voxels, so the
+/// work grows with the surface rather than the volume. Such a cube takes the
+/// material at its center, so `material` should only vary near the surface.
+/// Materials index into [`SparseVoxelOctree::palette`], which starts out empty.
+pub fn voxelize_sdf(
+    depth: u32,
+    sdf: impl Sdf,
+    material: impl Fn(Vec3) -> u32 + Sync,
+) -> SparseVoxelOctree {
+    SparseVoxelOctree::from_regions(depth, |min: UVec3, size| {
+        let center = min.as_vec3() + size as f32 / 2.0;
+        // Furthest any voxel center in the cube is from the cube's center.
+        let reach = (size - 1) as f32 * 3_f32.sqrt() / 2.0;
+        let distance = sdf.distance(center);
+
+        if distance > reach {
+            Fill::Uniform(None)
+        } else if distance <= -reach {
+            Fill::Uniform(Some(Voxel {
+                material: material(center),
+            }))
+        } else {
+            Fill::Mixed
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use glam::vec3;
+
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-4, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn shapes_measure_distances_to_their_surfaces() {
+        let cuboid = Cuboid {
+            half_extents: vec3(1.0, 2.0, 3.0),
+        };
+        assert_close(cuboid.distance(Vec3::ZERO), -1.0);
+        assert_close(cuboid.distance(vec3(4.0, 6.0, 3.0)), 5.0);
+
+        let capsule = Capsule {
+            a: Vec3::ZERO,
+            b: vec3(0.0, 10.0, 0.0),
+            radius: 2.0,
+        };
+        assert_close(capsule.distance(vec3(5.0, 4.0, 0.0)), 3.0);
+        assert_close(capsule.distance(vec3(0.0, -3.0, 0.0)), 1.0);
+
+        let torus = Torus {
+            major_radius: 8.0,
+            minor_radius: 2.0,
+        };
+        assert_close(torus.distance(vec3(0.0, 0.0, 8.0)), -2.0);
+        assert_close(torus.distance(Vec3::ZERO), 6.0);
+
+        let ball = Sphere { radius: 2.0 }
+            .scale(3.0)
+            .translate(vec3(10.0, 0.0, 0.0));
+        assert_close(ball.distance(Vec3::ZERO), 4.0);
+
+        let rotated = cuboid.rotate(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2));
+        assert_close(rotated.distance(vec3(2.0, 0.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn combinators_follow_set_operations() {
+        let a = Sphere { radius: 4.0 };
+        let b = Sphere { radius: 4.0 }.translate(vec3(6.0, 0.0, 0.0));
+        let (middle, inside_a) = (vec3(3.0, 0.0, 0.0), vec3(-2.0, 0.0, 0.0));
+
+        assert!(a.union(b).distance(inside_a) < 0.0);
+        assert!(a.intersect(b).distance(middle) < 0.0);
+        assert!(a.intersect(b).distance(inside_a) > 0.0);
+        assert!(a.subtract(b).distance(middle) > 0.0);
+        assert!(a.subtract(b).distance(inside_a) < 0.0);
+
+        let gap = vec3(3.0, 4.5, 0.0);
+        assert!(a.union(b).distance(gap) > 0.0);
+        assert!(a.smooth_union(b, 8.0).distance(gap) < 0.0);
+        assert_close(a.smooth_union(b, 4.0).distance(vec3(-6.0, 0.0, 0.0)), 2.0);
+    }
+
+    #[test]
+    fn voxelizing_matches_testing_every_voxel_center() {
+        let shape = Cuboid {
+            half_extents: vec3(9.0, 5.0, 7.0),
+        }
+        .rotate(Quat::from_rotation_y(0.6))
+        .smooth_union(
+            Torus {
+                major_radius: 8.0,
+                minor_radius: 3.0,
+            }
+            .translate(vec3(0.0, 6.0, 0.0)),
+            3.0,
+        )
+        .subtract(Capsule {
+            a: vec3(-20.0, 2.0, 0.0),
+            b: vec3(20.0, 2.0, 0.0),
+            radius: 3.5,
+        })
+        .translate(Vec3::splat(16.0));
+        let material = |p: Vec3| (p.y > 16.0) as u32;
+
+        let voxelized = voxelize_sdf(5, shape, material);
+        let expected = SparseVoxelOctree::from_fn(5, |x, y, z| {
+            let center = vec3(x as f32, y as f32, z as f32) + 0.5;
+            (shape.distance(center) <= 0.0).then(|| Voxel {
+                material: material(center),
+            })
+        });
+
+        assert!(voxelized.root() == expected.root());
+    }
+
+    #[test]
+    fn voxelizing_only_visits_cubes_near_the_surface() {
+        struct Counted<'a>(Sphere, &'a AtomicUsize);
+
+        impl Sdf for Counted<'_> {
+            fn distance(&self, p: Vec3) -> f32 {
+                self.1.fetch_add(1, Ordering::Relaxed);
+                self.0.distance(p - 64.0)
+            }
+        }
+
+        let calls = AtomicUsize::new(0);
+        let svo = voxelize_sdf(7, Counted(Sphere { radius: 50.0 }, &calls), |_| 0);
+
+        assert!(svo.get(64, 64, 64).is_some());
+        assert!(svo.get(64, 64, 120).is_none());
+        assert!(calls.into_inner() < 128 * 128 * 128 / 10);
+    }
+}
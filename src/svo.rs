@@ -1,6 +1,33 @@
-use rand::random;
-use shared::{Material, PackedNode, Voxel, TREE_DEPTH};
+use std::{collections::HashMap, mem::size_of};
 
+use glam::{IVec3, UVec3, Vec3};
+use rayon::prelude::*;
+use shared::{Material, PackedNode, Voxel};
+
+use crate::{
+    palette::MaterialPalette,
+    query::{Aabb, Blocks, Region},
+};
+
+/// Edited boxes kept apart before [`SparseVoxelOctree`] merges them into one.
+const MAX_DIRTY_REGIONS: usize = 64;
+
+/// Deepest tree whose size, `2^depth` voxels per axis, still fits in a `u32`.
+pub const MAX_DEPTH: u32 = 31;
+
+/// Smallest octant [`SparseVoxelOctree::from_regions`] hands to another thread.
+pub const PARALLEL_SIZE: u32 = 16;
+
+/// What [`SparseVoxelOctree::from_regions`] is told about a cube of voxels.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Fill {
+    /// Every voxel in the cube is the same, so it becomes one leaf.
+    Uniform(Option<Voxel>),
+    /// The cube has to be split into its octants.
+    Mixed,
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub enum Node {
     Branch { children: Box<[Self; 8]> },
     Leaf(Option<Voxel>),
@@ -8,62 +35,538 @@ pub enum Node {
 
 pub struct PackedSparseVoxelOctree {
     pub root: PackedNode,
+    pub depth: u32,
     pub nodes: Vec<[PackedNode; 8]>,
+    /// What each branch in `nodes` is drawn as when the shader stops above its
+    /// leaves, see [`lod`]. Always derived from `nodes` and `voxels`.
+    pub lods: Vec<PackedNode>,
     pub voxels: Vec<Voxel>,
+    pub materials: Vec<Material>,
+}
+
+impl PackedSparseVoxelOctree {
+    /// Bytes the node and voxel buffers take up on the GPU.
+    pub fn size_in_bytes(&self) -> usize {
+        self.nodes.len() * size_of::<[PackedNode; 8]>()
+            + self.lods.len() * size_of::<PackedNode>()
+            + self.voxels.len() * size_of::<Voxel>()
+            + self.materials.len() * size_of::<Material>()
+    }
+
+    /// [`SparseVoxelOctree::raycast`] over the packed nodes, descending them the
+    /// way the shader does.
+    pub fn raycast(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<RayHit> {
+        raycast(self.depth, origin, direction, max_distance, |cell| {
+            let mut node = self.root;
+            let mut node_min = UVec3::ZERO;
+            let mut size = 1 << self.depth;
+
+            while !node.is_leaf() {
+                size /= 2;
+                let upper = cell.cmpge(node_min + size);
+                node_min += UVec3::select(upper, UVec3::splat(size), UVec3::ZERO);
+                node = self.nodes[node.0 as usize][upper.bitmask() as usize];
+            }
+
+            let voxel = (!node.is_empty()).then(|| &self.voxels[(node.0 & !(1 << 31)) as usize]);
+            (voxel, node_min, size)
+        })
+    }
+
+    /// Rebuilds an editable tree, copying out subtrees a DAG shares. Node and
+    /// voxel indices must be in range, as they are for trees packed by
+    /// [`SparseVoxelOctree`] or loaded by [`crate::scene`].
+    pub fn unpack(&self) -> SparseVoxelOctree {
+        let mut palette = MaterialPalette::new();
+        for material in &self.materials {
+            palette.add(*material);
+        }
+
+        SparseVoxelOctree {
+            root: self.unpack_node(self.root),
+            max_depth: self.depth,
+            palette,
+            dirty: vec![],
+        }
+    }
+
+    fn unpack_node(&self, node: PackedNode) -> Node {
+        if node.is_empty() {
+            Node::Leaf(None)
+        } else if node.is_leaf() {
+            Node::Leaf(Some(self.voxels[(node.0 & !(1 << 31)) as usize]))
+        } else {
+            Node::Branch {
+                children: Box::new(
+                    self.nodes[node.0 as usize].map(|child| self.unpack_node(child)),
+                ),
+            }
+        }
+    }
+}
+
+/// First solid voxel along a ray, found by [`SparseVoxelOctree::raycast`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RayHit<'a> {
+    /// Finest-level cell the ray entered the solid voxel through. Large uniform
+    /// leaves report the cell on the face that was hit, not their corner.
+    pub position: UVec3,
+    /// Outward normal of the face the ray entered through, or zero when the ray
+    /// started inside the voxel.
+    pub normal: IVec3,
+    /// Distance from the origin in voxels.
+    pub distance: f32,
+    pub voxel: &'a Voxel,
 }
 
 pub struct SparseVoxelOctree {
     root: Node,
     max_depth: u32,
+    palette: MaterialPalette,
+    /// Half-open boxes edited since the last [`SparseVoxelOctree::take_dirty`].
+    dirty: Vec<(UVec3, UVec3)>,
 }
 
 impl SparseVoxelOctree {
     pub fn pack(&self) -> PackedSparseVoxelOctree {
         let mut nodes: Vec<[PackedNode; 8]> = vec![];
+        let mut lods: Vec<PackedNode> = vec![];
         let mut voxels: Vec<Voxel> = vec![];
 
-        let root = self.root.pack_traverse(&mut nodes, &mut voxels);
+        let root = self.root.pack_traverse(&mut nodes, &mut lods, &mut voxels);
 
         PackedSparseVoxelOctree {
             voxels,
             nodes,
+            lods,
+            root,
+            depth: self.max_depth,
+            materials: self.palette.materials().to_vec(),
+        }
+    }
+
+    /// Packs the tree as a directed acyclic graph: identical subtrees and identical
+    /// voxels are stored once and referenced from every place they occur. The
+    /// layout is the same as [`Self::pack`], so the shader reads either.
+    pub fn pack_dag(&self) -> PackedSparseVoxelOctree {
+        let mut packer = DagPacker::default();
+        let root = packer.pack(&self.root);
+
+        PackedSparseVoxelOctree {
+            voxels: packer.voxels,
+            nodes: packer.nodes,
+            lods: packer.lods,
             root,
+            depth: self.max_depth,
+            materials: self.palette.materials().to_vec(),
         }
     }
 
+    /// How many times smaller [`Self::pack_dag`] is than [`Self::pack`].
+    pub fn dag_compression_ratio(&self) -> f32 {
+        self.pack().size_in_bytes() as f32 / self.pack_dag().size_in_bytes().max(1) as f32
+    }
+
     pub fn new(depth: u32) -> Self {
+        let mut palette = MaterialPalette::new();
+
+        Self {
+            root: Node::new(depth, 0, 0, 0, &mut palette),
+            max_depth: depth,
+            palette,
+            dirty: vec![],
+        }
+    }
+
+    /// Builds a tree of the given depth bottom-up, calling `voxel` once for every
+    /// finest voxel. Uniform regions collapse as soon as their eight children are
+    /// built, so memory grows with the finished tree rather than with `8^depth`.
+    /// Materials index into [`Self::palette`], which starts out empty.
+    pub fn from_fn(depth: u32, voxel: impl Fn(u32, u32, u32) -> Option<Voxel> + Sync) -> Self {
+        Self::from_regions(depth, |min, size| match size {
+            1 => Fill::Uniform(voxel(min.x, min.y, min.z)),
+            _ => Fill::Mixed,
+        })
+    }
+
+    /// Builds a tree of the given depth top-down, asking `fill` about every cube of
+    /// `size` voxels at `min` before splitting it, so a region known to be uniform
+    /// costs one call however large it is. `fill` must not answer [`Fill::Mixed`]
+    /// for a single voxel. Octants of at least [`PARALLEL_SIZE`] voxels per axis
+    /// are built in parallel. Materials index into [`Self::palette`], which starts
+    /// out empty.
+    pub fn from_regions(depth: u32, fill: impl Fn(UVec3, u32) -> Fill + Sync) -> Self {
+        Self {
+            root: Node::build(UVec3::ZERO, 2_u32.pow(depth), &fill),
+            max_depth: depth,
+            palette: MaterialPalette::new(),
+            dirty: vec![],
+        }
+    }
+
+    /// Builds the smallest tree, at least one level deep, holding a dense
+    /// `dims.x * dims.y * dims.z` grid stored with x varying fastest, then y, then
+    /// z. Cells of the tree beyond `dims` are empty.
+    pub fn from_dense(voxels: &[Option<Voxel>], dims: UVec3) -> Self {
+        assert_eq!(
+            voxels.len(),
+            (dims.x * dims.y * dims.z) as usize,
+            "dense grid does not match its dimensions"
+        );
+
+        // Lookups descend at least one level, so even a single voxel gets a
+        // tree two voxels wide.
+        let depth = dims
+            .max_element()
+            .next_power_of_two()
+            .trailing_zeros()
+            .max(1);
+        Self::from_fn(depth, |x, y, z| {
+            if UVec3::new(x, y, z).cmplt(dims).all() {
+                voxels[(x + dims.x * (y + dims.y * z)) as usize]
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Copies the half-open box `[min, max)` into a dense grid laid out as
+    /// [`Self::from_dense`] expects. Cells outside the tree are empty.
+    pub fn to_dense(&self, min: UVec3, max: UVec3) -> Vec<Option<Voxel>> {
+        let dims = max.max(min) - min;
+        let mut voxels = vec![None; (dims.x * dims.y * dims.z) as usize];
+        for (p, voxel) in self.voxels_in(Aabb::new(min, max)) {
+            let p = p - min;
+            voxels[(p.x + dims.x * (p.y + dims.y * p.z)) as usize] = Some(*voxel);
+        }
+
+        voxels
+    }
+
+    /// A tree of the given depth without any voxels or materials.
+    pub fn empty(depth: u32) -> Self {
         Self {
-            root: Node::new(depth, 0, 0, 0),
+            root: Node::Leaf(None),
             max_depth: depth,
+            palette: MaterialPalette::new(),
+            dirty: vec![],
         }
     }
 
+    pub fn depth(&self) -> u32 {
+        self.max_depth
+    }
+
+    /// Size of the root's octants, zero for a depth 0 tree whose root is a single
+    /// voxel.
+    fn half_size(&self) -> u32 {
+        (1 << self.max_depth) >> 1
+    }
+
+    /// Materials the voxels of this tree index into.
+    pub fn palette(&self) -> &MaterialPalette {
+        &self.palette
+    }
+
+    pub fn palette_mut(&mut self) -> &mut MaterialPalette {
+        &mut self.palette
+    }
+
+    /// Material of the voxel at the given position, if there is one.
+    pub fn get_material(&self, x: u32, y: u32, z: u32) -> Option<&Material> {
+        self.get(x, y, z)
+            .and_then(|voxel| self.palette.get(voxel.material))
+    }
+
     pub fn get(&self, x: u32, y: u32, z: u32) -> Option<&Voxel> {
-        self.root.get(x, y, z, 2_u32.pow(self.max_depth - 1))
+        self.root.get(x, y, z, self.half_size())
     }
 
+    /// Copy of the node `depth` levels below the root that contains the given
+    /// voxel, i.e. what [`Self::insert`] at the same position and depth replaces.
+    /// Inside a larger uniform leaf this is a leaf of the same voxel.
+    pub fn subtree(&self, x: u32, y: u32, z: u32, depth: u32) -> Node {
+        self.subtree_ref(x, y, z, depth).clone()
+    }
+
+    /// [`Self::subtree`] without the copy.
+    pub(crate) fn subtree_ref(&self, x: u32, y: u32, z: u32, depth: u32) -> &Node {
+        self.root.subtree(x, y, z, self.half_size(), depth)
+    }
+
+    /// Replaces the node `depth` levels below the root that contains the given
+    /// voxel, first growing the tree to `depth` levels if it is shallower.
+    /// Positions outside the grown tree are ignored.
     pub fn insert(&mut self, x: u32, y: u32, z: u32, node: Node, depth: u32) {
+        let size = 2_u32.pow(self.max_depth.max(depth));
+        if x >= size || y >= size || z >= size {
+            return;
+        }
+
         if depth > self.max_depth {
             self.max_depth = depth;
+            mark_dirty(
+                &mut self.dirty,
+                UVec3::ZERO,
+                UVec3::splat(2_u32.pow(self.max_depth)),
+            );
+        } else {
+            let size = 2_u32.pow(self.max_depth - depth);
+            let min = UVec3::new(x, y, z) / size * size;
+            mark_dirty(&mut self.dirty, min, min + size);
         }
+        self.root.insert(x, y, z, node, self.half_size(), depth);
+    }
+
+    /// Replaces the whole tree with `root`, `depth` levels deep, such as to undo an
+    /// [`Self::insert`] that deepened it.
+    pub fn replace_root(&mut self, root: Node, depth: u32) {
+        let size = 2_u32.pow(self.max_depth.max(depth));
+        mark_dirty(&mut self.dirty, UVec3::ZERO, UVec3::splat(size));
+        self.root = root;
+        self.max_depth = depth;
+    }
+
+    /// Empties the voxel at the given position. Positions outside the tree are
+    /// ignored.
+    pub fn remove(&mut self, x: u32, y: u32, z: u32) {
+        self.insert(x, y, z, Node::Leaf(None), self.max_depth);
+    }
+
+    /// Empties every voxel in the half-open box `[min, max)`.
+    pub fn clear_box(&mut self, min: UVec3, max: UVec3) {
+        mark_dirty(&mut self.dirty, min, max);
         self.root
-            .insert(x, y, z, node, 2_u32.pow(self.max_depth - 1), depth);
+            .clear_box(min, max, UVec3::ZERO, 2_u32.pow(self.max_depth));
+    }
+
+    /// Returns and forgets the boxes edited since the last call, for uploaders
+    /// that only re-pack what changed.
+    pub fn take_dirty(&mut self) -> Vec<(UVec3, UVec3)> {
+        std::mem::take(&mut self.dirty)
+    }
+
+    /// Casts a ray through the tree, where each finest voxel is a unit cube and
+    /// the tree spans `[0, 2^depth)` on every axis. Returns the first solid voxel
+    /// within `max_distance` of `origin`. Empty subtrees and uniform leaves are
+    /// crossed in a single step, as in the shader's traversal.
+    pub fn raycast(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<RayHit> {
+        let size = 2_u32.pow(self.max_depth);
+        raycast(self.max_depth, origin, direction, max_distance, |cell| {
+            self.root.leaf(cell, size)
+        })
+    }
+
+    /// Solid voxels inside `region`, grouped into the largest uniform cubes the
+    /// tree stores, so a solid leaf inside the region is a single block.
+    pub fn blocks<R: Region>(&self, region: R) -> Blocks<R> {
+        Blocks::new(&self.root, 2_u32.pow(self.max_depth), region)
+    }
+
+    /// Every solid voxel inside `region` with its position.
+    pub fn voxels_in<R: Region>(&self, region: R) -> impl Iterator<Item = (UVec3, &Voxel)> {
+        self.blocks(region).flat_map(|block| block.voxels())
+    }
+
+    pub fn is_region_empty(&self, region: impl Region) -> bool {
+        self.blocks(region).next().is_none()
+    }
+
+    /// Number of solid voxels inside `region`.
+    pub fn count_solid(&self, region: impl Region) -> u64 {
+        self.blocks(region).map(|block| block.volume()).sum()
+    }
+
+    /// Every solid leaf as its minimum corner, size in voxels and voxel, in
+    /// octant order. A uniform leaf comes out once however many voxels it spans.
+    pub fn iter(&self) -> impl Iterator<Item = (UVec3, u32, &Voxel)> {
+        let size = 2_u32.pow(self.max_depth);
+        self.blocks(Aabb::new(UVec3::ZERO, UVec3::splat(size)))
+            .map(|block| (block.min, block.size, block.voxel))
+    }
+
+    /// Like [`Self::iter`], with the voxels open to in-place edits. Each leaf is
+    /// marked dirty as it is handed out. Editing leaves this way does not merge
+    /// siblings that end up identical; [`Self::insert`] does.
+    pub fn iter_mut(&mut self) -> IterMut {
+        IterMut {
+            stack: vec![(&mut self.root, UVec3::ZERO, 2_u32.pow(self.max_depth))],
+            dirty: &mut self.dirty,
+        }
+    }
+
+    /// Walks the tree depth first, calling `visit` with every node, its minimum
+    /// corner, size in voxels and depth below the root. Children of a branch are
+    /// visited when `visit` returns `true` for it and it is shallower than
+    /// `max_depth`.
+    pub fn visit(&self, max_depth: u32, mut visit: impl FnMut(&Node, UVec3, u32, u32) -> bool) {
+        let mut stack = vec![(&self.root, UVec3::ZERO, 2_u32.pow(self.max_depth), 0)];
+        while let Some((node, min, size, depth)) = stack.pop() {
+            let descend = visit(node, min, size, depth);
+            if let (Node::Branch { children }, true) = (node, descend && depth < max_depth) {
+                let half = size / 2;
+                for (i, child) in children.iter().enumerate().rev() {
+                    stack.push((child, min + octant_offset(i) * half, half, depth + 1));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn root(&self) -> &Node {
+        &self.root
+    }
+}
+
+/// Records that the half-open box `[min, max)` may have changed. Past
+/// [`MAX_DIRTY_REGIONS`] boxes they are merged into their bounding box, which
+/// keeps checking them cheap at the cost of revisiting some unchanged nodes.
+fn mark_dirty(dirty: &mut Vec<(UVec3, UVec3)>, min: UVec3, max: UVec3) {
+    if dirty.len() >= MAX_DIRTY_REGIONS {
+        let bounds = dirty
+            .drain(..)
+            .fold((min, max), |(min, max), (a, b)| (min.min(a), max.max(b)));
+        dirty.push(bounds);
+    } else {
+        dirty.push((min, max));
     }
 }
 
+/// Position of child `index` within its parent, in units of the child's size.
+fn octant_offset(index: usize) -> UVec3 {
+    let index = index as u32;
+    UVec3::new(index & 1, (index >> 1) & 1, (index >> 2) & 1)
+}
+
+/// Solid leaves of a tree open to edits, returned by
+/// [`SparseVoxelOctree::iter_mut`].
+pub struct IterMut<'a> {
+    stack: Vec<(&'a mut Node, UVec3, u32)>,
+    dirty: &'a mut Vec<(UVec3, UVec3)>,
+}
+
+impl<'a> Iterator for IterMut<'a> {
+    type Item = (UVec3, u32, &'a mut Voxel);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, min, size)) = self.stack.pop() {
+            match node {
+                Node::Branch { children } => {
+                    let half = size / 2;
+                    for (i, child) in children.iter_mut().enumerate().rev() {
+                        self.stack
+                            .push((child, min + octant_offset(i) * half, half));
+                    }
+                }
+                Node::Leaf(Some(voxel)) => {
+                    mark_dirty(self.dirty, min, min + size);
+                    return Some((min, size, voxel));
+                }
+                Node::Leaf(None) => {}
+            }
+        }
+
+        None
+    }
+}
+
+/// Axis of the smallest component, preferring x, then y, on ties.
+fn min_axis(v: Vec3) -> usize {
+    if v.x <= v.y && v.x <= v.z {
+        0
+    } else if v.y <= v.z {
+        1
+    } else {
+        2
+    }
+}
+
+/// Hierarchical traversal behind both raycasts. `leaf` returns the leaf holding a
+/// cell along with that leaf's minimum corner and size; the ray then either hits
+/// it or jumps to the face it leaves the leaf through.
+fn raycast<'a>(
+    depth: u32,
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    leaf: impl Fn(UVec3) -> (Option<&'a Voxel>, UVec3, u32),
+) -> Option<RayHit<'a>> {
+    let tree_size = 1_i32 << depth;
+    let direction = direction.normalize();
+    let inv_direction = shader::safe_inverse(direction);
+    let step = inv_direction.signum().as_ivec3();
+
+    let t0 = -origin * inv_direction;
+    let t1 = (Vec3::splat(tree_size as f32) - origin) * inv_direction;
+    let t_near = t0.min(t1);
+    let t_enter = t_near.max_element();
+    let t_exit = t0.max(t1).min_element();
+    if t_exit < t_enter.max(0.0) {
+        return None;
+    }
+
+    let mut t = t_enter.max(0.0);
+    let mut normal = IVec3::ZERO;
+    if t_enter > 0.0 {
+        let axis = min_axis(-t_near);
+        normal[axis] = -step[axis];
+    }
+    let mut cell = (origin + direction * t)
+        .floor()
+        .clamp(Vec3::ZERO, Vec3::splat((tree_size - 1) as f32))
+        .as_ivec3();
+
+    while t <= max_distance {
+        let (voxel, node_min, node_size) = leaf(cell.as_uvec3());
+        if let Some(voxel) = voxel {
+            return Some(RayHit {
+                position: cell.as_uvec3(),
+                normal,
+                distance: t,
+                voxel,
+            });
+        }
+
+        let node_min = node_min.as_ivec3();
+        let node_max = node_min + node_size as i32 - 1;
+        let exit_planes = IVec3::select(step.cmpgt(IVec3::ZERO), node_max + 1, node_min);
+        let t_planes = (exit_planes.as_vec3() - origin) * inv_direction;
+        let axis = min_axis(t_planes);
+
+        t = t_planes[axis];
+        cell = (origin + direction * t)
+            .floor()
+            .clamp(node_min.as_vec3(), node_max.as_vec3())
+            .as_ivec3();
+        cell[axis] = if step[axis] > 0 {
+            node_max[axis] + 1
+        } else {
+            node_min[axis] - 1
+        };
+        normal = -step * IVec3::AXES[axis];
+
+        if cell.cmplt(IVec3::ZERO).any() || cell.cmpge(IVec3::splat(tree_size)).any() {
+            return None;
+        }
+    }
+
+    None
+}
+
 impl Node {
-    pub fn new(depth: u32, x: usize, y: usize, z: usize) -> Self {
+    /// Gradient cube of the given depth, adding one material per voxel to `palette`.
+    pub fn new(depth: u32, x: usize, y: usize, z: usize, palette: &mut MaterialPalette) -> Self {
         if depth == 0 {
             Node::Leaf(Some(Voxel {
-                material: Material {
+                material: palette.add(Material {
                     albedo: [
-                        (0x40 + (x as u8) * 0x11) as f32 / 255.0,
-                        (0x40 + (y as u8) * 0x11) as f32 / 255.0,
-                        (0x40 + (z as u8) * 0x11) as f32 / 255.0,
+                        0x40_u8.wrapping_add((x as u8).wrapping_mul(0x11)) as f32 / 255.0,
+                        0x40_u8.wrapping_add((y as u8).wrapping_mul(0x11)) as f32 / 255.0,
+                        0x40_u8.wrapping_add((z as u8).wrapping_mul(0x11)) as f32 / 255.0,
                     ],
                     // albedo: [random(), random(), random()],
                     roughness: 1.0,
-                },
+                    emission: 0.0,
+                }),
             }))
         } else {
             let child_depth = depth - 1;
@@ -72,9 +575,10 @@ impl Node {
                 children: Box::new(std::array::from_fn(|i| {
                     Node::new(
                         child_depth,
-                        x + ((i >> 0) & 1) * child_size,
+                        x + (i & 1) * child_size,
                         y + ((i >> 1) & 1) * child_size,
                         z + ((i >> 2) & 1) * child_size,
+                        palette,
                     )
                 })),
             }
@@ -84,6 +588,7 @@ impl Node {
     fn pack_traverse(
         &self,
         nodes: &mut Vec<[PackedNode; 8]>,
+        lods: &mut Vec<PackedNode>,
         voxels: &mut Vec<Voxel>,
     ) -> PackedNode {
         match self {
@@ -91,11 +596,12 @@ impl Node {
                 let mut packed_children = [PackedNode(u32::MAX); 8];
 
                 for (i, child) in children.iter().enumerate() {
-                    packed_children[i] = child.pack_traverse(nodes, voxels);
+                    packed_children[i] = child.pack_traverse(nodes, lods, voxels);
                 }
 
                 let branch_idx = nodes.len();
                 nodes.push(packed_children);
+                lods.push(lod(&packed_children, lods, voxels));
 
                 PackedNode(branch_idx as u32)
             }
@@ -112,7 +618,7 @@ impl Node {
         match self {
             Node::Leaf(voxel) => voxel.as_ref(),
             Node::Branch { children } => {
-                let index = ((x >= size) as usize) << 0
+                let index = (x >= size) as usize
                     | ((y >= size) as usize) << 1
                     | ((z >= size) as usize) << 2;
 
@@ -121,6 +627,61 @@ impl Node {
         }
     }
 
+    /// Node of `size` voxels per axis at `min`, split for as long as `fill` says
+    /// it is mixed, for [`SparseVoxelOctree::from_regions`].
+    fn build(min: UVec3, size: u32, fill: &(impl Fn(UVec3, u32) -> Fill + Sync)) -> Node {
+        if let Fill::Uniform(voxel) = fill(min, size) {
+            return Node::Leaf(voxel);
+        }
+        assert!(size > 1, "a single voxel cannot be mixed");
+
+        let half = size / 2;
+        let child = |i| Node::build(min + octant_offset(i) * half, half, fill);
+        let children: Box<[Node; 8]> = if half >= PARALLEL_SIZE {
+            let children: Vec<Node> = (0..8).into_par_iter().map(child).collect();
+            children.into_boxed_slice().try_into().unwrap()
+        } else {
+            Box::new(std::array::from_fn(child))
+        };
+
+        let mut node = Node::Branch { children };
+        node.collapse();
+        node
+    }
+
+    /// Leaf containing `cell` in a node spanning `size` voxels per axis, with its
+    /// minimum corner and size relative to this node.
+    fn leaf(&self, cell: UVec3, size: u32) -> (Option<&Voxel>, UVec3, u32) {
+        let mut node = self;
+        let mut node_min = UVec3::ZERO;
+        let mut size = size;
+
+        loop {
+            match node {
+                Node::Leaf(voxel) => return (voxel.as_ref(), node_min, size),
+                Node::Branch { children } => {
+                    size /= 2;
+                    let upper = cell.cmpge(node_min + size);
+                    node_min += UVec3::select(upper, UVec3::splat(size), UVec3::ZERO);
+                    node = &children[upper.bitmask() as usize];
+                }
+            }
+        }
+    }
+
+    fn subtree(&self, x: u32, y: u32, z: u32, size: u32, depth: u32) -> &Node {
+        match self {
+            Node::Branch { children } if depth > 0 => {
+                let index = (x >= size) as usize
+                    | ((y >= size) as usize) << 1
+                    | ((z >= size) as usize) << 2;
+
+                children[index].subtree(x % size, y % size, z % size, size / 2, depth - 1)
+            }
+            _ => self,
+        }
+    }
+
     pub fn insert(&mut self, x: u32, y: u32, z: u32, node: Node, size: u32, depth: u32) {
         if depth == 0 {
             *self = node;
@@ -129,14 +690,15 @@ impl Node {
 
         match self {
             Node::Branch { children } => {
-                let index = ((x >= size) as usize) << 0
+                let index = (x >= size) as usize
                     | ((y >= size) as usize) << 1
                     | ((z >= size) as usize) << 2;
 
-                children[index].insert(x % size, y % size, z % size, node, size / 2, depth - 1)
+                children[index].insert(x % size, y % size, z % size, node, size / 2, depth - 1);
+                self.collapse();
             }
             Node::Leaf(voxel) => {
-                let voxel = voxel.clone();
+                let voxel = *voxel;
                 *self = Node::Branch {
                     children: Box::new(std::array::from_fn(|_| Node::Leaf(voxel))),
                 };
@@ -145,4 +707,772 @@ impl Node {
             }
         }
     }
+
+    /// `node_min` and `size` are the bounds of this node in voxels.
+    fn clear_box(&mut self, min: UVec3, max: UVec3, node_min: UVec3, size: u32) {
+        let node_max = node_min + size;
+
+        if min.cmpge(node_max).any() || max.cmple(node_min).any() {
+            return;
+        }
+        if min.cmple(node_min).all() && max.cmpge(node_max).all() {
+            *self = Node::Leaf(None);
+            return;
+        }
+
+        match self {
+            Node::Leaf(None) => {}
+            Node::Leaf(Some(voxel)) => {
+                let voxel = *voxel;
+                *self = Node::Branch {
+                    children: Box::new(std::array::from_fn(|_| Node::Leaf(Some(voxel)))),
+                };
+
+                self.clear_box(min, max, node_min, size);
+            }
+            Node::Branch { children } => {
+                let child_size = size / 2;
+                for (i, child) in children.iter_mut().enumerate() {
+                    let offset = UVec3::new(i as u32 & 1, (i as u32 >> 1) & 1, (i as u32 >> 2) & 1);
+                    child.clear_box(min, max, node_min + offset * child_size, child_size);
+                }
+
+                self.collapse();
+            }
+        }
+    }
+
+    /// Replaces a branch whose children are all identical leaves with that leaf, so
+    /// edits never leave uniform regions split up.
+    fn collapse(&mut self) {
+        if let Node::Branch { children } = self {
+            if let Node::Leaf(first) = children[0] {
+                if children
+                    .iter()
+                    .all(|child| matches!(child, Node::Leaf(voxel) if *voxel == first))
+                {
+                    *self = Node::Leaf(first);
+                }
+            }
+        }
+    }
+}
+
+/// What a branch with the given packed children is drawn as once it is too small
+/// on screen to descend: the voxel most of its children are drawn as, or empty
+/// when fewer than half of them are solid. Branch children are looked up in
+/// `lods`, so children must be resolved before their parents.
+pub fn lod(children: &[PackedNode; 8], lods: &[PackedNode], voxels: &[Voxel]) -> PackedNode {
+    let drawn = children.map(|child| match child.is_leaf() {
+        true => child,
+        false => lods[child.0 as usize],
+    });
+    let voxel = |node: PackedNode| voxels[(node.0 & !(1 << 31)) as usize];
+
+    let solid = || drawn.iter().filter(|node| !node.is_empty());
+    if solid().count() < 4 {
+        return PackedNode(u32::MAX);
+    }
+
+    let mut best = (PackedNode(u32::MAX), 0);
+    for node in solid() {
+        let count = solid()
+            .filter(|other| voxel(**other) == voxel(*node))
+            .count();
+        if count > best.1 {
+            best = (*node, count);
+        }
+    }
+
+    best.0
+}
+
+/// [`lod`] of every branch in `nodes`, which must store children before their
+/// parents as [`SparseVoxelOctree::pack`] and [`SparseVoxelOctree::pack_dag`] do.
+pub fn compute_lods(nodes: &[[PackedNode; 8]], voxels: &[Voxel]) -> Vec<PackedNode> {
+    let mut lods = Vec::with_capacity(nodes.len());
+    for children in nodes {
+        let lod = lod(children, &lods, voxels);
+        lods.push(lod);
+    }
+
+    lods
+}
+
+const VOXEL_WORDS: usize = size_of::<Voxel>() / 4;
+
+/// Bottom-up packer behind [`SparseVoxelOctree::pack_dag`]. Children are packed
+/// before their parent, so two subtrees are identical exactly when their packed
+/// children are, and a lookup on the eight child words finds shared branches.
+#[derive(Default)]
+struct DagPacker {
+    nodes: Vec<[PackedNode; 8]>,
+    lods: Vec<PackedNode>,
+    voxels: Vec<Voxel>,
+    branches: HashMap<[u32; 8], PackedNode>,
+    leaves: HashMap<[u32; VOXEL_WORDS], PackedNode>,
+}
+
+impl DagPacker {
+    fn pack(&mut self, node: &Node) -> PackedNode {
+        match node {
+            Node::Branch { children } => {
+                let mut packed_children = [PackedNode(u32::MAX); 8];
+                for (packed_child, child) in packed_children.iter_mut().zip(children.iter()) {
+                    *packed_child = self.pack(child);
+                }
+
+                let (nodes, lods, voxels) = (&mut self.nodes, &mut self.lods, &self.voxels);
+                *self
+                    .branches
+                    .entry(bytemuck::cast(packed_children))
+                    .or_insert_with(|| {
+                        nodes.push(packed_children);
+                        lods.push(lod(&packed_children, lods, voxels));
+                        PackedNode(nodes.len() as u32 - 1)
+                    })
+            }
+            Node::Leaf(Some(voxel)) => {
+                let voxels = &mut self.voxels;
+                *self
+                    .leaves
+                    .entry(bytemuck::cast(*voxel))
+                    .or_insert_with(|| {
+                        voxels.push(*voxel);
+                        PackedNode((voxels.len() as u32 - 1) | (1 << 31))
+                    })
+            }
+            Node::Leaf(None) => PackedNode(u32::MAX),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use shader::{HitResult, Ray};
+
+    use shared::ShaderConstants;
+
+    use super::*;
+
+    const TREE_DEPTH: u32 = 3;
+
+    /// Inserts a voxel of the given albedo at `depth`, with its own palette entry.
+    fn paint(svo: &mut SparseVoxelOctree, x: u32, y: u32, z: u32, albedo: [f32; 3], depth: u32) {
+        let material = svo.palette_mut().add(Material {
+            albedo,
+            roughness: 1.0,
+            emission: 0.0,
+        });
+        svo.insert(x, y, z, Node::Leaf(Some(Voxel { material })), depth);
+    }
+
+    fn cast_in(
+        svo: &SparseVoxelOctree,
+        world_origin: [f32; 3],
+        world_size: f32,
+        origin: Vec3,
+        direction: Vec3,
+    ) -> HitResult {
+        cast_lod(svo, world_origin, world_size, 0.0, origin, direction)
+    }
+
+    /// [`cast_in`] with level of detail, for a frame one pixel high whose field of
+    /// view makes that pixel two units wide at unit distance.
+    fn cast_lod(
+        svo: &SparseVoxelOctree,
+        world_origin: [f32; 3],
+        world_size: f32,
+        lod_bias: f32,
+        origin: Vec3,
+        direction: Vec3,
+    ) -> HitResult {
+        let packed = svo.pack();
+        let constants = ShaderConstants {
+            width: 1,
+            height: 1,
+            time: 0.0,
+            sample_count: 0,
+            chunk_grid: 1,
+            tree_depth: packed.depth,
+            world_origin,
+            world_size,
+            camera_position: [0.0; 3],
+            camera_yaw: 0.0,
+            camera_pitch: 0.0,
+            camera_fov: std::f32::consts::FRAC_PI_2,
+            lod_bias,
+        };
+        let mut ray = Ray {
+            origin,
+            direction: direction.normalize(),
+            t: 0.0,
+        };
+
+        ray.traverse(
+            &constants,
+            &[packed.root],
+            &packed.nodes,
+            &packed.lods,
+            &packed.voxels,
+            &packed.materials,
+        )
+    }
+
+    fn cast(svo: &SparseVoxelOctree, origin: Vec3, direction: Vec3) -> HitResult {
+        cast_in(svo, [0.0; 3], (1 << svo.depth()) as f32, origin, direction)
+    }
+
+    #[test]
+    fn traverse_skips_empty_space_to_the_first_voxel() {
+        let mut svo = SparseVoxelOctree::empty(TREE_DEPTH);
+        paint(&mut svo, 5, 2, 6, [1.0, 0.0, 0.0], TREE_DEPTH);
+        paint(&mut svo, 5, 2, 1, [0.0, 1.0, 0.0], TREE_DEPTH);
+
+        let hit = cast(&svo, vec3(5.5, 2.5, 20.0), vec3(0.0, 0.0, -1.0));
+
+        assert!(hit.exists);
+        assert_eq!(hit.material.albedo, [1.0, 0.0, 0.0]);
+        assert_eq!(hit.normal, vec3(0.0, 0.0, 1.0));
+        assert!((hit.position.z - 7.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn traverse_misses_rays_passing_beside_geometry() {
+        let mut svo = SparseVoxelOctree::empty(TREE_DEPTH);
+        paint(&mut svo, 5, 2, 6, [1.0, 0.0, 0.0], TREE_DEPTH);
+
+        assert!(!cast(&svo, vec3(0.5, 7.5, 20.0), vec3(0.0, 0.0, -1.0)).exists);
+        assert!(!cast(&svo, vec3(3.5, 2.5, 20.0), vec3(0.1, 0.0, -1.0)).exists);
+        assert!(!cast(&svo, vec3(-1.0, -1.0, -1.0), vec3(-1.0, 0.0, 0.0)).exists);
+    }
+
+    #[test]
+    fn traverse_hits_large_uniform_leaves_on_their_face() {
+        let mut svo = SparseVoxelOctree::empty(TREE_DEPTH);
+        paint(&mut svo, 4, 0, 0, [0.0, 0.0, 1.0], 1);
+
+        let hit = cast(&svo, vec3(12.0, 1.5, 2.5), vec3(-1.0, 0.1, 0.0));
+
+        assert!(hit.exists);
+        assert_eq!(hit.normal, vec3(1.0, 0.0, 0.0));
+        assert!((hit.position.x - 8.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn traverse_reports_the_voxel_at_the_entry_face() {
+        let svo = SparseVoxelOctree::new(TREE_DEPTH);
+
+        let hit = cast(&svo, vec3(-10.0, 4.5, 3.5), vec3(1.0, 0.0, 0.0));
+
+        assert!(hit.exists);
+        assert_eq!(hit.normal, vec3(-1.0, 0.0, 0.0));
+        assert_eq!(Some(&hit.material), svo.get_material(0, 4, 3));
+    }
+
+    #[test]
+    fn traverse_agrees_with_point_queries_along_diagonal_rays() {
+        let mut svo = SparseVoxelOctree::empty(TREE_DEPTH);
+        for i in 0..8 {
+            paint(&mut svo, i, i, 7 - i, [i as f32, 0.0, 0.0], TREE_DEPTH);
+        }
+
+        for i in 0..8 {
+            let origin = vec3(i as f32 + 0.5, i as f32 + 0.5, 20.0);
+            let hit = cast(&svo, origin, vec3(0.0, 0.0, -1.0));
+
+            assert!(hit.exists);
+            assert_eq!(hit.material.albedo[0], i as f32);
+            assert!((hit.position.z - (8 - i) as f32).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn traverse_maps_deeper_trees_into_world_bounds() {
+        let mut svo = SparseVoxelOctree::empty(5);
+        paint(&mut svo, 31, 0, 0, [0.0, 1.0, 0.0], 5);
+
+        let hit = cast_in(
+            &svo,
+            [-4.0, -4.0, -4.0],
+            8.0,
+            vec3(3.9, -3.9, 10.0),
+            vec3(0.0, 0.0, -1.0),
+        );
+
+        assert!(hit.exists);
+        assert_eq!(hit.normal, vec3(0.0, 0.0, 1.0));
+        assert!((hit.position.z + 3.75).abs() < 1e-4);
+        assert!(
+            !cast_in(
+                &svo,
+                [-4.0; 3],
+                8.0,
+                vec3(3.6, -3.9, 10.0),
+                vec3(0.0, 0.0, -1.0)
+            )
+            .exists
+        );
+    }
+
+    #[test]
+    fn traverse_stops_descending_at_small_distant_branches() {
+        // A 2x2x2 block with a hole the ray passes straight through.
+        let mut svo = SparseVoxelOctree::empty(TREE_DEPTH);
+        for i in 0..8 {
+            paint(
+                &mut svo,
+                4 + (i & 1),
+                4 + (i >> 1 & 1),
+                4 + (i >> 2),
+                [1.0, 0.0, 0.0],
+                TREE_DEPTH,
+            );
+        }
+        svo.remove(4, 4, 4);
+        svo.remove(4, 4, 5);
+        let origin = vec3(4.5, 4.5, 100.0);
+
+        assert!(!cast(&svo, origin, Vec3::NEG_Z).exists);
+
+        // Far enough for the block to be under a pixel, but not its parent.
+        let hit = cast_lod(&svo, [0.0; 3], 8.0, 0.015, origin, Vec3::NEG_Z);
+        assert!(hit.exists);
+        assert_eq!(hit.material.albedo, [1.0, 0.0, 0.0]);
+        assert!((hit.position.z - 6.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn lods_pick_the_majority_voxel_or_empty_when_sparse() {
+        let voxel = |material| Node::Leaf(Some(Voxel { material }));
+        let mut svo = SparseVoxelOctree::empty(1);
+        for i in 0..8 {
+            svo.insert(i & 1, i >> 1 & 1, i >> 2, voxel(1 + (i % 3 == 0) as u32), 1);
+        }
+
+        let packed = svo.pack();
+        let lod = packed.lods[packed.root.0 as usize];
+        assert_eq!(packed.voxels[(lod.0 & !(1 << 31)) as usize].material, 1);
+        let dag = svo.pack_dag();
+        assert_eq!(dag.lods, compute_lods(&dag.nodes, &dag.voxels));
+
+        for i in 0..5 {
+            svo.remove(i & 1, i >> 1 & 1, i >> 2);
+        }
+        let packed = svo.pack();
+        assert!(packed.lods[packed.root.0 as usize].is_empty());
+    }
+
+    #[test]
+    fn edits_outside_the_tree_are_ignored() {
+        let voxel = Voxel { material: 0 };
+        let mut svo = SparseVoxelOctree::from_fn(TREE_DEPTH, |_, _, _| Some(voxel));
+
+        svo.remove(9, 0, 0);
+        svo.remove(100, 3, 3);
+        svo.insert(3, 8, 3, Node::Leaf(None), TREE_DEPTH);
+
+        assert!(*svo.root() == Node::Leaf(Some(voxel)));
+        assert!(svo.take_dirty().is_empty());
+    }
+
+    #[test]
+    fn remove_collapses_emptied_branches() {
+        let mut svo = SparseVoxelOctree::empty(TREE_DEPTH);
+        paint(&mut svo, 5, 2, 6, [1.0, 0.0, 0.0], TREE_DEPTH);
+        assert_eq!(svo.pack().nodes.len(), TREE_DEPTH as usize);
+
+        svo.remove(5, 2, 6);
+
+        let packed = svo.pack();
+        assert!(packed.nodes.is_empty());
+        assert!(packed.root.is_empty());
+    }
+
+    #[test]
+    fn insert_merges_uniform_branches() {
+        let mut svo = SparseVoxelOctree::empty(TREE_DEPTH);
+        let voxel = Voxel { material: 0 };
+        for i in 0..8 {
+            let (x, y, z) = (i & 1, (i >> 1) & 1, (i >> 2) & 1);
+            svo.insert(x, y, z, Node::Leaf(Some(voxel)), TREE_DEPTH);
+        }
+
+        // Only the root and the 4^3 octant above the merged 2^3 block stay split.
+        let packed = svo.pack();
+        assert_eq!(packed.nodes.len(), 2);
+        assert_eq!(packed.voxels.len(), 1);
+        assert_eq!(svo.get(1, 1, 1), Some(&voxel));
+    }
+
+    #[test]
+    fn depth_zero_trees_hold_a_single_voxel() {
+        let mut svo = SparseVoxelOctree::empty(0);
+        let voxel = Voxel { material: 3 };
+        assert_eq!(svo.get(0, 0, 0), None);
+
+        svo.insert(0, 0, 0, Node::Leaf(Some(voxel)), 0);
+        svo.insert(1, 0, 0, Node::Leaf(None), 0);
+        assert_eq!(svo.get(0, 0, 0), Some(&voxel));
+        assert_eq!(svo.subtree(0, 0, 0, 0), Node::Leaf(Some(voxel)));
+        assert_eq!(svo.depth(), 0);
+
+        svo.remove(0, 0, 0);
+        assert_eq!(svo.get(0, 0, 0), None);
+    }
+
+    #[test]
+    fn clear_box_empties_only_the_box() {
+        let mut svo = SparseVoxelOctree::new(TREE_DEPTH);
+
+        svo.clear_box(UVec3::new(0, 0, 0), UVec3::new(8, 8, 3));
+
+        for z in 0..8 {
+            assert_eq!(svo.get(4, 5, z).is_some(), z >= 3);
+        }
+
+        svo.clear_box(UVec3::ZERO, UVec3::splat(8));
+        assert!(svo.pack().root.is_empty());
+    }
+
+    /// Tree of the given depth colored like a 3D checkerboard, so every 2^3 block
+    /// is the same.
+    fn checkerboard(depth: u32) -> SparseVoxelOctree {
+        let mut svo = SparseVoxelOctree::empty(depth);
+        let size = 1 << depth;
+        for (x, y, z) in
+            (0..size * size * size).map(|i| (i % size, i / size % size, i / size / size))
+        {
+            let material = (x + y + z) % 2;
+            svo.insert(x, y, z, Node::Leaf(Some(Voxel { material })), depth);
+        }
+        svo
+    }
+
+    /// Point query on a packed tree, descending the node buffer like the shader.
+    fn packed_get(packed: &PackedSparseVoxelOctree, x: u32, y: u32, z: u32) -> Option<Voxel> {
+        let mut node = packed.root;
+        let mut size = 1 << packed.depth;
+        while !node.is_leaf() {
+            size /= 2;
+            let index = (x & size != 0) as usize
+                | ((y & size != 0) as usize) << 1
+                | ((z & size != 0) as usize) << 2;
+            node = packed.nodes[node.0 as usize][index];
+        }
+
+        (!node.is_empty()).then(|| packed.voxels[(node.0 & !(1 << 31)) as usize])
+    }
+
+    #[test]
+    fn pack_dag_is_equivalent_to_the_tree() {
+        let mut carved = checkerboard(4);
+        carved.clear_box(UVec3::new(3, 0, 5), UVec3::new(11, 7, 16));
+        paint(&mut carved, 9, 9, 9, [0.5; 3], 4);
+
+        for svo in [SparseVoxelOctree::new(TREE_DEPTH), checkerboard(4), carved] {
+            let (packed, dag) = (svo.pack(), svo.pack_dag());
+            let size = 1 << svo.depth();
+
+            for (x, y, z) in
+                (0..size * size * size).map(|i| (i % size, i / size % size, i / size / size))
+            {
+                let expected = svo.get(x, y, z).copied();
+                assert_eq!(
+                    packed_get(&packed, x, y, z),
+                    expected,
+                    "pack at {x} {y} {z}"
+                );
+                assert_eq!(
+                    packed_get(&dag, x, y, z),
+                    expected,
+                    "pack_dag at {x} {y} {z}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pack_dag_shares_repeated_subtrees() {
+        let svo = checkerboard(4);
+
+        // One distinct branch per level and one voxel per color.
+        let dag = svo.pack_dag();
+        assert_eq!(dag.nodes.len(), 4);
+        assert_eq!(dag.voxels.len(), 2);
+        assert_eq!(svo.pack().nodes.len(), 1 + 8 + 64 + 512);
+        assert!(svo.dag_compression_ratio() > 100.0);
+
+        // Every voxel in the gradient cube differs, so there is nothing to share.
+        let gradient = SparseVoxelOctree::new(TREE_DEPTH);
+        assert_eq!(
+            gradient.pack_dag().size_in_bytes(),
+            gradient.pack().size_in_bytes()
+        );
+    }
+
+    #[test]
+    fn raycast_reports_the_cell_face_and_distance_of_the_first_voxel() {
+        let mut svo = SparseVoxelOctree::empty(TREE_DEPTH);
+        paint(&mut svo, 4, 0, 0, [0.0, 0.0, 1.0], 1);
+        paint(&mut svo, 1, 2, 3, [1.0, 0.0, 0.0], TREE_DEPTH);
+
+        let hit = svo
+            .raycast(vec3(12.0, 1.5, 2.5), Vec3::NEG_X, 100.0)
+            .unwrap();
+        assert_eq!(hit.position, UVec3::new(7, 1, 2));
+        assert_eq!(hit.normal, IVec3::X);
+        assert!((hit.distance - 4.0).abs() < 1e-5);
+        assert_eq!(
+            svo.palette().get(hit.voxel.material).unwrap().albedo,
+            [0.0, 0.0, 1.0]
+        );
+
+        let hit = svo.raycast(vec3(1.5, 2.5, -3.0), Vec3::Z, 100.0).unwrap();
+        assert_eq!(
+            (hit.position, hit.normal),
+            (UVec3::new(1, 2, 3), IVec3::NEG_Z)
+        );
+        assert!((hit.distance - 6.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn raycast_stops_at_max_distance() {
+        let mut svo = SparseVoxelOctree::empty(TREE_DEPTH);
+        paint(&mut svo, 6, 6, 6, [1.0; 3], TREE_DEPTH);
+        let origin = vec3(6.5, 6.5, 0.5);
+
+        assert!(svo.raycast(origin, Vec3::Z, 5.0).is_none());
+        assert!(svo.raycast(origin, Vec3::Z, 5.5).is_some());
+        assert!(svo.raycast(origin, Vec3::NEG_Z, 100.0).is_none());
+    }
+
+    #[test]
+    fn raycast_from_inside_a_voxel_hits_it_immediately() {
+        let svo = SparseVoxelOctree::new(TREE_DEPTH);
+
+        let hit = svo
+            .raycast(vec3(2.5, 3.5, 4.5), vec3(1.0, -1.0, 0.3), 10.0)
+            .unwrap();
+
+        assert_eq!(hit.position, UVec3::new(2, 3, 4));
+        assert_eq!((hit.normal, hit.distance), (IVec3::ZERO, 0.0));
+    }
+
+    #[test]
+    fn raycast_agrees_with_the_packed_tree_and_the_shader() {
+        let mut rng = StdRng::seed_from_u64(16);
+        let depth = 5;
+        let size = 1 << depth;
+        let mut svo = SparseVoxelOctree::empty(depth);
+        for _ in 0..60 {
+            let [x, y, z] = [(); 3].map(|_| rng.gen_range(0..size));
+            let material = rng.gen_range(0..4);
+            paint(
+                &mut svo,
+                x,
+                y,
+                z,
+                [material as f32; 3],
+                rng.gen_range(2..=depth),
+            );
+        }
+        let packed = svo.pack();
+        let dag = svo.pack_dag();
+
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let origin = Vec3::from([(); 3].map(|_| rng.gen_range(-8.0..size as f32 + 8.0)));
+            let target = Vec3::from([(); 3].map(|_| rng.gen_range(0.0..size as f32)));
+            let direction = (target - origin).normalize();
+
+            let hit = svo.raycast(origin, direction, f32::INFINITY);
+            assert_eq!(hit, packed.raycast(origin, direction, f32::INFINITY));
+            assert_eq!(hit, dag.raycast(origin, direction, f32::INFINITY));
+
+            let shader_hit = cast(&svo, origin, direction);
+            assert_eq!(hit.is_some(), shader_hit.exists, "{origin} {direction}");
+            let Some(hit) = hit else { continue };
+            hits += 1;
+
+            assert_eq!(hit.normal.as_vec3(), shader_hit.normal);
+            assert!(
+                shader_hit
+                    .position
+                    .distance(origin + direction * hit.distance)
+                    < 1e-3
+            );
+            assert_eq!(
+                svo.palette().get(hit.voxel.material),
+                Some(&shader_hit.material)
+            );
+            let p = hit.position;
+            assert_eq!(svo.get(p.x, p.y, p.z), Some(hit.voxel));
+        }
+        assert!(hits > 500, "{hits}");
+    }
+
+    #[test]
+    fn iter_yields_each_solid_leaf_once() {
+        let mut svo = SparseVoxelOctree::empty(TREE_DEPTH);
+        svo.insert(4, 0, 0, Node::Leaf(Some(Voxel { material: 1 })), 1);
+        svo.insert(1, 2, 3, Node::Leaf(Some(Voxel { material: 2 })), TREE_DEPTH);
+
+        let leaves: Vec<_> = svo.iter().collect();
+        assert_eq!(
+            leaves,
+            [
+                (UVec3::new(1, 2, 3), 1, &Voxel { material: 2 }),
+                (UVec3::new(4, 0, 0), 4, &Voxel { material: 1 }),
+            ]
+        );
+
+        let gradient = SparseVoxelOctree::new(TREE_DEPTH);
+        assert_eq!(gradient.iter().count(), 512);
+        assert!(gradient
+            .iter()
+            .all(|(p, _, voxel)| gradient.get(p.x, p.y, p.z) == Some(voxel)));
+    }
+
+    #[test]
+    fn iter_mut_edits_voxels_and_marks_them_dirty() {
+        let mut svo = checkerboard(TREE_DEPTH);
+        svo.take_dirty();
+
+        for (_, _, voxel) in svo.iter_mut().filter(|(p, _, _)| p.x < 2) {
+            voxel.material = 7;
+        }
+
+        assert_eq!(svo.get(1, 5, 6), Some(&Voxel { material: 7 }));
+        assert_eq!(svo.get(2, 5, 6), Some(&Voxel { material: 1 }));
+        let dirty = svo.take_dirty();
+        assert!(dirty
+            .iter()
+            .any(|(min, max)| min.cmple(UVec3::new(1, 5, 6)).all()
+                && max.cmpgt(UVec3::new(1, 5, 6)).all()));
+    }
+
+    #[test]
+    fn visit_stops_at_the_depth_limit_and_when_asked() {
+        let svo = checkerboard(TREE_DEPTH);
+        let mut per_depth = [0; 4];
+        svo.visit(2, |_, _, size, depth| {
+            assert_eq!(size, 8 >> depth);
+            per_depth[depth as usize] += 1;
+            true
+        });
+        assert_eq!(per_depth, [1, 8, 64, 0]);
+
+        let mut visited = vec![];
+        svo.visit(TREE_DEPTH, |node, min, _, _| {
+            visited.push(min);
+            !matches!(node, Node::Branch { .. }) || min == UVec3::ZERO
+        });
+        // Only the branch at the origin opens up on each level.
+        assert_eq!(visited.len(), 1 + 8 + 8 + 8);
+    }
+
+    #[test]
+    fn from_fn_builds_the_same_tree_as_inserting() {
+        let built = SparseVoxelOctree::from_fn(TREE_DEPTH, |x, y, z| {
+            Some(Voxel {
+                material: (x + y + z) % 2,
+            })
+        });
+        assert!(built.root() == checkerboard(TREE_DEPTH).root());
+
+        let mut inserted = SparseVoxelOctree::empty(TREE_DEPTH);
+        inserted.insert(0, 4, 0, Node::Leaf(Some(Voxel { material: 3 })), 1);
+        inserted.insert(5, 1, 2, Node::Leaf(Some(Voxel { material: 1 })), TREE_DEPTH);
+        let built = SparseVoxelOctree::from_fn(TREE_DEPTH, |x, y, z| match (x, y, z) {
+            (0..=3, 4..=7, 0..=3) => Some(Voxel { material: 3 }),
+            (5, 1, 2) => Some(Voxel { material: 1 }),
+            _ => None,
+        });
+        assert!(built.root() == inserted.root());
+    }
+
+    #[test]
+    fn from_fn_collapses_uniform_worlds_into_one_leaf() {
+        let solid = SparseVoxelOctree::from_fn(6, |_, _, _| Some(Voxel { material: 2 }));
+        assert!(*solid.root() == Node::Leaf(Some(Voxel { material: 2 })));
+        assert_eq!(solid.depth(), 6);
+
+        let ground =
+            SparseVoxelOctree::from_fn(6, |_, y, _| (y < 32).then_some(Voxel { material: 0 }));
+        assert_eq!(ground.pack().nodes.len(), 1);
+    }
+
+    #[test]
+    fn from_regions_skips_cubes_it_is_told_are_uniform() {
+        let ground = |y: u32| (y < 20).then_some(Voxel { material: 0 });
+        let calls = std::sync::atomic::AtomicUsize::new(0);
+
+        let built = SparseVoxelOctree::from_regions(6, |min, size| {
+            calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            if min.y + size <= 20 || min.y >= 20 {
+                Fill::Uniform(ground(min.y))
+            } else {
+                Fill::Mixed
+            }
+        });
+
+        assert!(built.root() == SparseVoxelOctree::from_fn(6, |_, y, _| ground(y)).root());
+        assert!(calls.into_inner() < 1000);
+    }
+
+    #[test]
+    fn from_dense_builds_a_single_voxel_grid_one_level_deep() {
+        let voxel = Voxel { material: 4 };
+        let svo = SparseVoxelOctree::from_dense(&[Some(voxel)], UVec3::ONE);
+
+        assert_eq!(svo.depth(), 1);
+        assert_eq!(svo.get(0, 0, 0), Some(&voxel));
+        assert_eq!(svo.get(1, 1, 1), None);
+    }
+
+    #[test]
+    fn from_dense_pads_grids_to_the_next_power_of_two() {
+        let dims = UVec3::new(5, 3, 2);
+        let voxels: Vec<_> = (0..30)
+            .map(|i| (i % 4 != 0).then_some(Voxel { material: i }))
+            .collect();
+
+        let svo = SparseVoxelOctree::from_dense(&voxels, dims);
+
+        assert_eq!(svo.depth(), 3);
+        for z in 0..8 {
+            for y in 0..8 {
+                for x in 0..8 {
+                    let expected = if x < 5 && y < 3 && z < 2 {
+                        voxels[(x + 5 * (y + 3 * z)) as usize]
+                    } else {
+                        None
+                    };
+                    assert_eq!(svo.get(x, y, z).copied(), expected, "{x} {y} {z}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn to_dense_copies_a_box_and_round_trips_through_from_dense() {
+        let svo = checkerboard(TREE_DEPTH);
+        let (min, max) = (UVec3::new(2, 3, 1), UVec3::new(7, 5, 11));
+
+        let dense = svo.to_dense(min, max);
+
+        assert_eq!(dense.len(), 5 * 2 * 10);
+        for (i, voxel) in dense.iter().enumerate() {
+            let i = i as u32;
+            let p = min + UVec3::new(i % 5, i / 5 % 2, i / 10);
+            assert_eq!(voxel.as_ref(), svo.get(p.x, p.y, p.z).filter(|_| p.z < 8));
+        }
+
+        let size = UVec3::splat(1 << TREE_DEPTH);
+        let copy = SparseVoxelOctree::from_dense(&svo.to_dense(UVec3::ZERO, size), size);
+        assert!(copy.root() == svo.root());
+    }
 }
@@ -0,0 +1,362 @@
+//! Procedural terrain: a fractal noise heightmap layered into grass, dirt and
+//! stone, with caves carved out of the stone by 3D noise.
+//!
+//! All noise is value noise on integer lattices hashed with the seed, so a seed
+//! generates the same world however the work is split between threads. The tree
+//! is built with [`SparseVoxelOctree::from_regions`], which skips the air above
+//! the terrain in whole octants and builds the rest in parallel.
+
+use glam::{UVec3, Vec2, Vec3};
+use rayon::prelude::*;
+use shader::rng::hash;
+use shared::{Material, Voxel};
+
+use crate::svo::{Fill, SparseVoxelOctree};
+
+/// Palette indices of the generated materials.
+pub const GRASS: u32 = 0;
+pub const DIRT: u32 = 1;
+pub const STONE: u32 = 2;
+
+/// Voxels of dirt under the grass before stone starts.
+const DIRT_DEPTH: u32 = 3;
+/// Stone left between the dirt and the highest caves, so caves never open up in
+/// the surface.
+const CAVE_ROOF: u32 = 4;
+/// Octaves of cave noise. Caves need less detail than hills.
+const CAVE_OCTAVES: u32 = 2;
+
+#[derive(Clone, Debug)]
+pub struct TerrainOptions {
+    pub seed: u32,
+    /// The world spans `2^depth` voxels per axis.
+    pub depth: u32,
+    /// Mean surface height as a fraction of the world height.
+    pub base_height: f32,
+    /// Largest deviation of the surface from `base_height`, as a fraction of the
+    /// world height.
+    pub amplitude: f32,
+    /// Width of the largest hills in voxels.
+    pub hill_size: f32,
+    /// Octaves of heightmap noise, each half the size and amplitude of the last.
+    pub octaves: u32,
+    /// Width of the largest caves in voxels.
+    pub cave_size: f32,
+    /// Cave noise, between -1 and 1, above which stone is carved out. 1 or more
+    /// disables caves.
+    pub cave_threshold: f32,
+}
+
+impl Default for TerrainOptions {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            depth: 7,
+            base_height: 0.4,
+            amplitude: 0.2,
+            hill_size: 96.0,
+            octaves: 5,
+            cave_size: 24.0,
+            cave_threshold: 0.45,
+        }
+    }
+}
+
+/// Builds the terrain described by `options`, with [`GRASS`], [`DIRT`] and
+/// [`STONE`] in its palette.
+pub fn generate(options: &TerrainOptions) -> SparseVoxelOctree {
+    let heightmap = Heightmap::new(options);
+    let caves = Caves::new(options);
+
+    let voxel = |p: UVec3| {
+        let height = heightmap.height(p.x, p.z);
+        if p.y > height {
+            return None;
+        }
+
+        let depth = height - p.y;
+        let material = match depth {
+            0 => GRASS,
+            _ if depth <= DIRT_DEPTH => DIRT,
+            _ if depth > DIRT_DEPTH + CAVE_ROOF && caves.noise(p) > options.cave_threshold => {
+                return None
+            }
+            _ => STONE,
+        };
+
+        Some(Voxel { material })
+    };
+
+    let mut svo = SparseVoxelOctree::from_regions(options.depth, |min, size| {
+        let (lowest, highest) = heightmap.range(min.x, min.z, size);
+        let top = min.y + size - 1;
+
+        if min.y > highest {
+            Fill::Uniform(None)
+        } else if size == 1 {
+            Fill::Uniform(voxel(min))
+        } else if top + DIRT_DEPTH < lowest && options.cave_threshold >= 1.0 {
+            Fill::Uniform(Some(Voxel { material: STONE }))
+        } else {
+            Fill::Mixed
+        }
+    });
+
+    let palette = svo.palette_mut();
+    for albedo in [[0.3, 0.6, 0.2], [0.45, 0.3, 0.2], [0.5, 0.5, 0.5]] {
+        palette.add(Material {
+            albedo,
+            roughness: 1.0,
+            emission: 0.0,
+        });
+    }
+
+    svo
+}
+
+/// Surface height of every column, plus the lowest and highest height within
+/// every aligned power-of-two square, so a whole octant can be tested against
+/// the surface at once.
+struct Heightmap {
+    size: u32,
+    /// Level `i` holds the range of the `2^i` wide squares, `size >> i` per row.
+    levels: Vec<Vec<(u32, u32)>>,
+}
+
+impl Heightmap {
+    fn new(options: &TerrainOptions) -> Self {
+        let size = 2_u32.pow(options.depth);
+        let world = size as f32;
+
+        let heights: Vec<(u32, u32)> = (0..size * size)
+            .into_par_iter()
+            .map(|i| {
+                let column = Vec2::new((i % size) as f32, (i / size) as f32);
+                let noise = fbm2(options.seed, column / options.hill_size, options.octaves);
+                let height = (options.base_height + noise * options.amplitude) * world;
+                let height = height.clamp(0.0, world - 1.0) as u32;
+                (height, height)
+            })
+            .collect();
+
+        let mut levels = vec![heights];
+        while levels.last().unwrap().len() > 1 {
+            let below = levels.last().unwrap();
+            let width = size >> (levels.len() - 1);
+            let half = width / 2;
+            let level = (0..half * half)
+                .map(|i| {
+                    let (x, z) = (i % half * 2, i / half * 2);
+                    [(x, z), (x + 1, z), (x, z + 1), (x + 1, z + 1)]
+                        .map(|(x, z)| below[(x + z * width) as usize])
+                        .into_iter()
+                        .fold((u32::MAX, 0), |(low, high), (lowest, highest)| {
+                            (low.min(lowest), high.max(highest))
+                        })
+                })
+                .collect();
+            levels.push(level);
+        }
+
+        Self { size, levels }
+    }
+
+    fn height(&self, x: u32, z: u32) -> u32 {
+        self.levels[0][(x + z * self.size) as usize].0
+    }
+
+    /// Lowest and highest surface in the `size` wide square at `(x, z)`, which
+    /// must be aligned to its size.
+    fn range(&self, x: u32, z: u32, size: u32) -> (u32, u32) {
+        let level = size.trailing_zeros();
+        let width = self.size >> level;
+        self.levels[level as usize][((x >> level) + (z >> level) * width) as usize]
+    }
+}
+
+/// Cave noise with its lattice values computed up front, since it is sampled at
+/// every stone voxel and hashing eight corners per octave each time dominates
+/// generation.
+struct Caves {
+    cave_size: f32,
+    /// The lattice of each octave, `width^3` values covering the world.
+    octaves: Vec<(i32, Vec<f32>)>,
+}
+
+impl Caves {
+    fn new(options: &TerrainOptions) -> Self {
+        let seed = options.seed.wrapping_add(1);
+        let world = 2_u32.pow(options.depth) as f32;
+        let octaves = (0..CAVE_OCTAVES)
+            .map(|octave| {
+                let width = (world * (1 << octave) as f32 / options.cave_size).ceil() as i32 + 1;
+                let values = (0..width * width * width)
+                    .into_par_iter()
+                    .map(|i| {
+                        let (x, y, z) = (i % width, i / width % width, i / width / width);
+                        lattice(seed.wrapping_add(octave), x, y, z)
+                    })
+                    .collect();
+                (width, values)
+            })
+            .collect();
+
+        Self {
+            cave_size: options.cave_size,
+            octaves,
+        }
+    }
+
+    /// [`fbm3`] of the cave seed at `p`.
+    fn noise(&self, p: UVec3) -> f32 {
+        let p = p.as_vec3() / self.cave_size;
+        fbm(self.octaves.len() as u32, |octave, scale| {
+            let (width, values) = &self.octaves[octave as usize];
+            let index = |x, y, z| values[(x + (y + z * width) * width) as usize];
+            noise3(index, p * scale)
+        })
+    }
+}
+
+/// Uniform value in `[-1, 1]` at an integer lattice point.
+fn lattice(seed: u32, x: i32, y: i32, z: i32) -> f32 {
+    let h = hash(x as u32 ^ hash(y as u32 ^ hash(z as u32 ^ hash(seed))));
+    (h >> 8) as f32 / (1 << 23) as f32 - 1.0
+}
+
+fn smoothstep(t: f32) -> f32 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Value noise in `[-1, 1]` with one cell per unit between the values `lattice`
+/// gives at integer points.
+fn noise3(lattice: impl Fn(i32, i32, i32) -> f32, p: Vec3) -> f32 {
+    let cell = p.floor();
+    let [x, y, z] = cell.as_ivec3().to_array();
+    let t = (p - cell).to_array().map(smoothstep);
+
+    let plane = |z| {
+        let row = |y| lerp(lattice(x, y, z), lattice(x + 1, y, z), t[0]);
+        lerp(row(y), row(y + 1), t[1])
+    };
+    lerp(plane(z), plane(z + 1), t[2])
+}
+
+/// Sums `octaves` of `noise(octave, frequency)`, each at twice the frequency and
+/// half the amplitude of the last, normalized back to `[-1, 1]`.
+fn fbm(octaves: u32, noise: impl Fn(u32, f32) -> f32) -> f32 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut total = 0.0;
+    for octave in 0..octaves {
+        sum += amplitude * noise(octave, (1 << octave) as f32);
+        total += amplitude;
+        amplitude *= 0.5;
+    }
+
+    sum / total
+}
+
+/// Octaves of [`noise3`] over hashed lattices, one seed per octave.
+fn fbm3(seed: u32, p: Vec3, octaves: u32) -> f32 {
+    fbm(octaves, |octave, scale| {
+        let seed = seed.wrapping_add(octave);
+        noise3(|x, y, z| lattice(seed, x, y, z), p * scale)
+    })
+}
+
+/// [`fbm3`] on the plane `y = 0`.
+fn fbm2(seed: u32, p: Vec2, octaves: u32) -> f32 {
+    fbm3(seed, Vec3::new(p.x, 0.0, p.y), octaves)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(seed: u32) -> TerrainOptions {
+        TerrainOptions {
+            seed,
+            depth: 5,
+            hill_size: 16.0,
+            cave_size: 6.0,
+            cave_threshold: 0.2,
+            ..TerrainOptions::default()
+        }
+    }
+
+    #[test]
+    fn seeds_generate_the_same_world_every_time() {
+        let a = generate(&options(7));
+        let b = generate(&options(7));
+        let other = generate(&options(8));
+
+        assert!(a.root() == b.root());
+        assert!(a.root() != other.root());
+    }
+
+    #[test]
+    fn columns_are_grass_over_dirt_over_stone() {
+        let mut options = options(3);
+        options.cave_threshold = 1.0;
+        let svo = generate(&options);
+        let size = 1 << options.depth;
+
+        for (x, z) in [(0, 0), (5, 17), (31, 31), (12, 3)] {
+            let column: Vec<_> = (0..size)
+                .rev()
+                .filter_map(|y| svo.get(x, y, z).map(|voxel| voxel.material))
+                .collect();
+
+            assert_eq!(column[0], GRASS, "column {x} {z}");
+            assert!(column[1..=DIRT_DEPTH as usize].iter().all(|m| *m == DIRT));
+            assert!(column[DIRT_DEPTH as usize + 1..]
+                .iter()
+                .all(|m| *m == STONE));
+            assert_eq!(svo.get(x, 0, z).map(|voxel| voxel.material), Some(STONE));
+        }
+    }
+
+    #[test]
+    fn caves_carve_only_stone_below_the_roof() {
+        let solid = generate(&TerrainOptions {
+            cave_threshold: 1.0,
+            ..options(5)
+        });
+        let caves = generate(&options(5));
+        let size = 1 << 5;
+
+        let mut carved = 0;
+        for i in 0..size * size * size {
+            let (x, y, z) = (i % size, i / size % size, i / size / size);
+            match (solid.get(x, y, z), caves.get(x, y, z)) {
+                (Some(voxel), None) => {
+                    assert_eq!(voxel.material, STONE);
+                    assert!(solid.get(x, y + DIRT_DEPTH + CAVE_ROOF + 1, z).is_some());
+                    carved += 1;
+                }
+                (expected, actual) => assert_eq!(expected, actual),
+            }
+        }
+        assert!(carved > 0);
+    }
+
+    #[test]
+    fn heightmap_ranges_bound_their_columns() {
+        let heightmap = Heightmap::new(&options(1));
+
+        for (x, z, size) in [(0, 0, 32), (16, 8, 8), (4, 28, 4), (9, 9, 1)] {
+            let (lowest, highest) = heightmap.range(x, z, size);
+            let heights: Vec<u32> = (0..size * size)
+                .map(|i| heightmap.height(x + i % size, z + i / size))
+                .collect();
+
+            assert_eq!(lowest, *heights.iter().min().unwrap());
+            assert_eq!(highest, *heights.iter().max().unwrap());
+        }
+    }
+}
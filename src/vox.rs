@@ -0,0 +1,895 @@
+//! Import and export of MagicaVoxel `.vox` files.
+//!
+//! MagicaVoxel is Z-up while the tracer is Y-up, so a file position `(x, y, z)`
+//! lands at `(x, z, -y)` before the whole scene is shifted to start at the origin.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, io,
+    ops::Mul,
+    path::Path,
+};
+
+use bytemuck::Zeroable;
+use glam::{ivec3, IVec3, UVec3};
+use shared::{Material, Voxel};
+
+use crate::{
+    query::Aabb,
+    svo::{Node, SparseVoxelOctree},
+};
+
+/// Largest model MagicaVoxel accepts along each axis.
+const MAX_MODEL_SIZE: u32 = 256;
+
+#[derive(Debug)]
+pub enum VoxError {
+    Io(io::Error),
+    InvalidHeader,
+    Truncated,
+    InvalidChunk(&'static str),
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoxError::Io(err) => write!(f, "failed to read .vox file: {err}"),
+            VoxError::InvalidHeader => write!(f, "not a .vox file"),
+            VoxError::Truncated => write!(f, "unexpected end of .vox data"),
+            VoxError::InvalidChunk(chunk) => write!(f, "malformed {chunk} chunk"),
+        }
+    }
+}
+
+impl std::error::Error for VoxError {}
+
+impl From<io::Error> for VoxError {
+    fn from(err: io::Error) -> Self {
+        VoxError::Io(err)
+    }
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<SparseVoxelOctree, VoxError> {
+    parse(&fs::read(path)?)
+}
+
+/// Builds an octree just deep enough to hold every model of the scene. The
+/// tree's palette mirrors the file's, so voxel materials keep their .vox indices.
+pub fn parse(bytes: &[u8]) -> Result<SparseVoxelOctree, VoxError> {
+    let scene = Scene::parse(bytes)?;
+
+    let mut voxels = vec![];
+    scene.place_models(|position, color| {
+        voxels.push((ivec3(position.x, position.z, -position.y), color))
+    })?;
+
+    let Some(min) = voxels.iter().map(|(position, _)| *position).reduce(IVec3::min) else {
+        return Ok(SparseVoxelOctree::empty(1));
+    };
+    let max = voxels
+        .iter()
+        .map(|(position, _)| *position)
+        .fold(min, IVec3::max);
+    let extent = (max - min + 1).max_element() as u32;
+    let depth = extent.next_power_of_two().trailing_zeros().max(1);
+
+    let mut svo = SparseVoxelOctree::empty(depth);
+    for material in scene.materials() {
+        svo.palette_mut().add(material);
+    }
+    for (position, color) in voxels {
+        let p = (position - min).as_uvec3();
+        let voxel = Voxel {
+            material: color as u32,
+        };
+        svo.insert(p.x, p.y, p.z, Node::Leaf(Some(voxel)), depth);
+    }
+
+    Ok(svo)
+}
+
+pub fn save(svo: &SparseVoxelOctree, path: impl AsRef<Path>) -> Result<(), VoxError> {
+    fs::write(path, encode(svo))?;
+    Ok(())
+}
+
+/// Writes the tree as a .vox scene. [`parse`] reads back the same voxels, but
+/// like any .vox scene shifts them to start at the origin and builds a tree just
+/// deep enough to hold them, so a tree whose voxels do not touch its minimum
+/// corner comes back moved and possibly shallower. Worlds wider than 256 voxels
+/// are split into 256³ models, each placed by its own nTRN node. Materials are
+/// fitted into the 255 palette slots of the format, see [`quantize`].
+pub fn encode(svo: &SparseVoxelOctree) -> Vec<u8> {
+    let (slots, palette) = quantize(svo);
+    let size = 2_u32.pow(svo.depth());
+
+    // File-space voxels of every model, keyed by the model's place in the grid
+    // of 256³ models.
+    let mut models: BTreeMap<[u32; 3], Vec<[u8; 4]>> = BTreeMap::new();
+    for block in svo.blocks(Aabb::new(UVec3::ZERO, UVec3::splat(size))) {
+        let color = slots[block.voxel.material as usize];
+        for (p, _) in block.voxels() {
+            let file = UVec3::new(p.x, size - 1 - p.z, p.y);
+            let local = file % MAX_MODEL_SIZE;
+            models
+                .entry((file / MAX_MODEL_SIZE).to_array())
+                .or_default()
+                .push([local.x as u8, local.y as u8, local.z as u8, color]);
+        }
+    }
+
+    let mut body = Writer::default();
+    for (key, voxels) in &models {
+        let model_size = model_size(UVec3::from(*key), size);
+        body.chunk(b"SIZE", |chunk| {
+            for extent in model_size.to_array() {
+                chunk.i32(extent as i32);
+            }
+        });
+        body.chunk(b"XYZI", |chunk| {
+            chunk.i32(voxels.len() as i32);
+            for voxel in voxels {
+                chunk.bytes.extend_from_slice(voxel);
+            }
+        });
+    }
+
+    // Scene graph: a root transform holding a group of one transform and shape
+    // per model, the layout MagicaVoxel itself writes.
+    body.transform(0, 1, None);
+    body.chunk(b"nGRP", |chunk| {
+        chunk.i32(1);
+        chunk.dict(&[]);
+        chunk.i32(models.len() as i32);
+        for i in 0..models.len() as i32 {
+            chunk.i32(2 + 2 * i);
+        }
+    });
+    for (i, key) in models.keys().enumerate() {
+        let key = UVec3::from(*key);
+        // Shapes are centered on their translation, rounding down.
+        let translation = (key * MAX_MODEL_SIZE + model_size(key, size) / 2).as_ivec3();
+        let id = 2 + 2 * i as i32;
+
+        body.transform(id, id + 1, Some(translation));
+        body.chunk(b"nSHP", |chunk| {
+            chunk.i32(id + 1);
+            chunk.dict(&[]);
+            chunk.i32(1);
+            chunk.i32(i as i32);
+            chunk.dict(&[]);
+        });
+    }
+
+    body.chunk(b"RGBA", |chunk| {
+        for material in &palette[1..] {
+            let [r, g, b] = material
+                .albedo
+                .map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+            chunk.bytes.extend_from_slice(&[r, g, b, 0xff]);
+        }
+        chunk.bytes.extend_from_slice(&[0; 4]);
+    });
+    for (i, material) in palette.iter().enumerate().skip(1) {
+        // The importer only reads roughness back for metals.
+        let (kind, key, value) = if material.emission > 0.0 {
+            ("_emit", "_emit", material.emission)
+        } else if material.roughness < 1.0 {
+            ("_metal", "_rough", material.roughness)
+        } else {
+            continue;
+        };
+
+        body.chunk(b"MATL", |chunk| {
+            chunk.i32(i as i32);
+            chunk.dict(&[("_type", kind), (key, &value.to_string())]);
+        });
+    }
+
+    let mut file = Writer::default();
+    file.bytes.extend_from_slice(b"VOX ");
+    file.i32(150);
+    file.bytes.extend_from_slice(b"MAIN");
+    file.i32(0);
+    file.i32(body.bytes.len() as i32);
+    file.bytes.extend_from_slice(&body.bytes);
+
+    file.bytes
+}
+
+/// Extent of the model at `key` in the grid of models covering a tree `size`
+/// voxels wide.
+fn model_size(key: UVec3, size: u32) -> UVec3 {
+    (UVec3::splat(size) - key * MAX_MODEL_SIZE).min(UVec3::splat(MAX_MODEL_SIZE))
+}
+
+/// Maps every material index the tree uses to a slot of a .vox palette, which
+/// has 255 usable entries after the reserved index 0, and returns the mapping
+/// along with the palette.
+///
+/// Trees whose voxels only use indices 1 to 255, like imported ones, keep them.
+/// Other trees using at most 255 materials get one slot per material, in index
+/// order. Only beyond that are materials grouped by color, with colors coarsened
+/// one bit per channel at a time until the groups fit, and each slot averages its
+/// group.
+fn quantize(svo: &SparseVoxelOctree) -> (Vec<u8>, [Material; 256]) {
+    let mut used = BTreeMap::new();
+    for (_, _, voxel) in svo.iter() {
+        let material = svo.palette().get(voxel.material).copied();
+        used.insert(voxel.material, material.unwrap_or_else(Material::zeroed));
+    }
+    let len = used.keys().last().map_or(0, |last| *last as usize + 1);
+
+    let mut palette = [Material::zeroed(); 256];
+    let mut slots = vec![0; len];
+    if used.keys().all(|&material| (1..256).contains(&material)) {
+        for (&index, material) in &used {
+            palette[index as usize] = *material;
+            slots[index as usize] = index as u8;
+        }
+        return (slots, palette);
+    }
+    if used.len() <= 255 {
+        for (slot, (&index, material)) in used.iter().enumerate() {
+            palette[slot + 1] = *material;
+            slots[index as usize] = slot as u8 + 1;
+        }
+        return (slots, palette);
+    }
+
+    for bits in (1..=8).rev() {
+        let mut groups: BTreeMap<[u8; 3], Vec<u32>> = BTreeMap::new();
+        for (&index, material) in &used {
+            let color = material
+                .albedo
+                .map(|c| ((c.clamp(0.0, 1.0) * 255.0).round() as u8) >> (8 - bits));
+            groups.entry(color).or_default().push(index);
+        }
+        if groups.len() > 255 {
+            continue;
+        }
+
+        for (slot, members) in groups.values().enumerate() {
+            let slot = slot + 1;
+            let count = members.len() as f32;
+            let mut average = Material::zeroed();
+            for material in members.iter().map(|index| &used[index]) {
+                for (sum, c) in average.albedo.iter_mut().zip(material.albedo) {
+                    *sum += c / count;
+                }
+                average.roughness += material.roughness / count;
+                average.emission += material.emission / count;
+            }
+
+            palette[slot] = average;
+            for &index in members {
+                slots[index as usize] = slot as u8;
+            }
+        }
+        break;
+    }
+
+    (slots, palette)
+}
+
+struct Model {
+    size: IVec3,
+    voxels: Vec<[u8; 4]>,
+}
+
+enum SceneNode {
+    Transform {
+        child: i32,
+        translation: IVec3,
+        rotation: Rotation,
+    },
+    Group {
+        children: Vec<i32>,
+    },
+    Shape {
+        model: i32,
+    },
+}
+
+#[derive(Default)]
+struct MaterialProperties {
+    kind: String,
+    roughness: Option<f32>,
+    emission: Option<f32>,
+}
+
+struct Scene {
+    models: Vec<Model>,
+    nodes: HashMap<i32, SceneNode>,
+    palette: [[u8; 4]; 256],
+    properties: HashMap<u8, MaterialProperties>,
+}
+
+impl Scene {
+    fn parse(bytes: &[u8]) -> Result<Self, VoxError> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        if reader.take(4).ok() != Some(b"VOX ".as_slice()) {
+            return Err(VoxError::InvalidHeader);
+        }
+        reader.i32()?;
+
+        let (id, mut main) = reader.chunk()?;
+        if id != *b"MAIN" {
+            return Err(VoxError::InvalidHeader);
+        }
+
+        let mut scene = Scene {
+            models: vec![],
+            nodes: HashMap::new(),
+            palette: default_palette(),
+            properties: HashMap::new(),
+        };
+        let mut size = None;
+
+        while !main.is_empty() {
+            let (id, mut chunk) = main.chunk()?;
+
+            match &id {
+                b"SIZE" => size = Some(ivec3(chunk.i32()?, chunk.i32()?, chunk.i32()?)),
+                b"XYZI" => {
+                    let size = size.take().ok_or(VoxError::InvalidChunk("XYZI"))?;
+                    let count = chunk.count()?;
+                    let voxels = (0..count)
+                        .map(|_| Ok(chunk.take(4)?.try_into().unwrap()))
+                        .collect::<Result<_, VoxError>>()?;
+                    scene.models.push(Model { size, voxels });
+                }
+                b"RGBA" => {
+                    for i in 1..256 {
+                        scene.palette[i] = chunk.take(4)?.try_into().unwrap();
+                    }
+                }
+                b"MATL" => {
+                    let id = chunk.i32()?;
+                    let attributes = chunk.dict()?;
+                    let float = |key| attributes.get(key).and_then(|v: &String| v.parse().ok());
+
+                    if let Ok(id) = u8::try_from(id) {
+                        scene.properties.insert(
+                            id,
+                            MaterialProperties {
+                                kind: attributes.get("_type").cloned().unwrap_or_default(),
+                                roughness: float("_rough"),
+                                emission: float("_emit"),
+                            },
+                        );
+                    }
+                }
+                b"nTRN" => {
+                    let id = chunk.i32()?;
+                    chunk.dict()?;
+                    let child = chunk.i32()?;
+                    chunk.i32()?; // reserved
+                    chunk.i32()?; // layer
+                    let frames = chunk.count()?;
+                    let frame = if frames > 0 {
+                        chunk.dict()?
+                    } else {
+                        HashMap::new()
+                    };
+
+                    let translation = match frame.get("_t") {
+                        Some(t) => parse_translation(t).ok_or(VoxError::InvalidChunk("nTRN"))?,
+                        None => IVec3::ZERO,
+                    };
+                    let rotation = match frame.get("_r") {
+                        Some(r) => r
+                            .parse()
+                            .ok()
+                            .and_then(Rotation::decode)
+                            .ok_or(VoxError::InvalidChunk("nTRN"))?,
+                        None => Rotation::IDENTITY,
+                    };
+
+                    scene.nodes.insert(
+                        id,
+                        SceneNode::Transform {
+                            child,
+                            translation,
+                            rotation,
+                        },
+                    );
+                }
+                b"nGRP" => {
+                    let id = chunk.i32()?;
+                    chunk.dict()?;
+                    let count = chunk.count()?;
+                    let children = (0..count).map(|_| chunk.i32()).collect::<Result<_, _>>()?;
+
+                    scene.nodes.insert(id, SceneNode::Group { children });
+                }
+                b"nSHP" => {
+                    let id = chunk.i32()?;
+                    chunk.dict()?;
+                    if chunk.count()? == 0 {
+                        return Err(VoxError::InvalidChunk("nSHP"));
+                    }
+                    // Further models are animation frames, only the first one is used.
+                    let model = chunk.i32()?;
+
+                    scene.nodes.insert(id, SceneNode::Shape { model });
+                }
+                _ => {}
+            }
+        }
+
+        Ok(scene)
+    }
+
+    /// Index 0 of a .vox palette means "no voxel", so it maps to an unused entry.
+    fn materials(&self) -> [Material; 256] {
+        std::array::from_fn(|i| {
+            let [r, g, b, _] = self.palette[i];
+            let properties = self.properties.get(&(i as u8));
+            let kind = properties.map_or("_diffuse", |p| p.kind.as_str());
+
+            Material {
+                albedo: [r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0],
+                roughness: match kind {
+                    "_metal" | "_glass" | "_blend" => {
+                        properties.and_then(|p| p.roughness).unwrap_or(0.0)
+                    }
+                    _ => 1.0,
+                },
+                emission: match kind {
+                    "_emit" => properties.and_then(|p| p.emission).unwrap_or(0.0),
+                    _ => 0.0,
+                },
+            }
+        })
+    }
+
+    /// Calls `place` with the file-space position and palette index of every voxel.
+    /// Files without a scene graph keep each model at its own coordinates.
+    fn place_models(&self, mut place: impl FnMut(IVec3, u8)) -> Result<(), VoxError> {
+        if self.nodes.contains_key(&0) {
+            return self.place_node(0, Rotation::IDENTITY, IVec3::ZERO, 0, &mut place);
+        }
+
+        for model in &self.models {
+            for [x, y, z, color] in &model.voxels {
+                place(ivec3(*x as i32, *y as i32, *z as i32), *color);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn place_node(
+        &self,
+        id: i32,
+        rotation: Rotation,
+        translation: IVec3,
+        depth: usize,
+        place: &mut impl FnMut(IVec3, u8),
+    ) -> Result<(), VoxError> {
+        // A well-formed graph is a tree, this only guards against cycles.
+        if depth > self.nodes.len() {
+            return Err(VoxError::InvalidChunk("nTRN"));
+        }
+
+        match self.nodes.get(&id) {
+            Some(SceneNode::Transform {
+                child,
+                translation: local_translation,
+                rotation: local_rotation,
+            }) => self.place_node(
+                *child,
+                rotation * *local_rotation,
+                translation + rotation.apply(*local_translation),
+                depth + 1,
+                place,
+            ),
+            Some(SceneNode::Group { children }) => children.iter().try_for_each(|child| {
+                self.place_node(*child, rotation, translation, depth + 1, place)
+            }),
+            Some(SceneNode::Shape { model }) => {
+                let model = usize::try_from(*model)
+                    .ok()
+                    .and_then(|model| self.models.get(model))
+                    .ok_or(VoxError::InvalidChunk("nSHP"))?;
+                let center = model.size / 2;
+
+                for [x, y, z, color] in &model.voxels {
+                    let local = ivec3(*x as i32, *y as i32, *z as i32) - center;
+                    place(translation + rotation.apply(local), *color);
+                }
+
+                Ok(())
+            }
+            None => Err(VoxError::InvalidChunk("nTRN")),
+        }
+    }
+}
+
+/// Signed permutation matrix stored by rows, as encoded in the `_r` frame attribute.
+#[derive(Clone, Copy)]
+struct Rotation([[i32; 3]; 3]);
+
+impl Rotation {
+    const IDENTITY: Self = Rotation([[1, 0, 0], [0, 1, 0], [0, 0, 1]]);
+
+    /// Bits 0-1 and 2-3 hold the column of the non-zero entry in the first and
+    /// second row, bits 4-6 flag negative entries in each row.
+    fn decode(bits: u8) -> Option<Self> {
+        let first = (bits & 3) as usize;
+        let second = ((bits >> 2) & 3) as usize;
+        if first > 2 || second > 2 || first == second {
+            return None;
+        }
+        let columns = [first, second, 3 - first - second];
+
+        let mut rows = [[0; 3]; 3];
+        for (row, column) in columns.into_iter().enumerate() {
+            rows[row][column] = if bits & (0x10 << row) != 0 { -1 } else { 1 };
+        }
+
+        Some(Rotation(rows))
+    }
+
+    fn apply(&self, v: IVec3) -> IVec3 {
+        let [a, b, c] = self.0.map(|row| IVec3::from_array(row).dot(v));
+        ivec3(a, b, c)
+    }
+}
+
+impl Mul for Rotation {
+    type Output = Rotation;
+
+    fn mul(self, rhs: Rotation) -> Rotation {
+        let mut rows = [[0; 3]; 3];
+        for (row, out) in rows.iter_mut().enumerate() {
+            for (column, value) in out.iter_mut().enumerate() {
+                *value = (0..3).map(|k| self.0[row][k] * rhs.0[k][column]).sum();
+            }
+        }
+        Rotation(rows)
+    }
+}
+
+fn parse_translation(value: &str) -> Option<IVec3> {
+    let mut parts = value.split_whitespace().map(|part| part.parse().ok());
+    let translation = ivec3(parts.next()??, parts.next()??, parts.next()??);
+
+    parts.next().is_none().then_some(translation)
+}
+
+/// The palette MagicaVoxel uses for files without an RGBA chunk: the 6x6x6 color
+/// cube without black, followed by red, green, blue and gray ramps.
+fn default_palette() -> [[u8; 4]; 256] {
+    const CUBE: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    const RAMP: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let cube = CUBE.into_iter().flat_map(|r| {
+        CUBE.into_iter()
+            .flat_map(move |g| CUBE.into_iter().map(move |b| [r, g, b, 0xff]))
+    });
+    let ramps = (0..4).flat_map(|channel| {
+        RAMP.into_iter().map(move |v| match channel {
+            0 => [v, 0, 0, 0xff],
+            1 => [0, v, 0, 0xff],
+            2 => [0, 0, v, 0xff],
+            _ => [v, v, v, 0xff],
+        })
+    });
+
+    let mut palette = [[0; 4]; 256];
+    for (entry, color) in palette[1..].iter_mut().zip(cube.take(215).chain(ramps)) {
+        *entry = color;
+    }
+
+    palette
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], VoxError> {
+        let end = self.position.checked_add(len).ok_or(VoxError::Truncated)?;
+        let bytes = self
+            .bytes
+            .get(self.position..end)
+            .ok_or(VoxError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, VoxError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn count(&mut self) -> Result<usize, VoxError> {
+        usize::try_from(self.i32()?).map_err(|_| VoxError::Truncated)
+    }
+
+    fn string(&mut self) -> Result<String, VoxError> {
+        let len = self.count()?;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn dict(&mut self) -> Result<HashMap<String, String>, VoxError> {
+        let len = self.count()?;
+        (0..len)
+            .map(|_| Ok((self.string()?, self.string()?)))
+            .collect()
+    }
+
+    /// Reads a chunk header and returns its id along with a reader over its content
+    /// followed by its children.
+    fn chunk(&mut self) -> Result<([u8; 4], Reader<'a>), VoxError> {
+        let id = self.take(4)?.try_into().unwrap();
+        let content = self.count()?;
+        let children = self.count()?;
+        let bytes = self.take(content + children)?;
+
+        Ok((id, Reader { bytes, position: 0 }))
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.i32(value.len() as i32);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    fn dict(&mut self, entries: &[(&str, &str)]) {
+        self.i32(entries.len() as i32);
+        for (key, value) in entries {
+            self.string(key);
+            self.string(value);
+        }
+    }
+
+    /// Writes a chunk without children whose content is produced by `content`.
+    fn chunk(&mut self, id: &[u8; 4], content: impl FnOnce(&mut Writer)) {
+        let mut chunk = Writer::default();
+        content(&mut chunk);
+
+        self.bytes.extend_from_slice(id);
+        self.i32(chunk.bytes.len() as i32);
+        self.i32(0);
+        self.bytes.extend_from_slice(&chunk.bytes);
+    }
+
+    /// Writes an nTRN node with a single frame, translated if `translation` is set.
+    fn transform(&mut self, id: i32, child: i32, translation: Option<IVec3>) {
+        self.chunk(b"nTRN", |chunk| {
+            chunk.i32(id);
+            chunk.dict(&[]);
+            chunk.i32(child);
+            chunk.i32(-1); // reserved
+            chunk.i32(0); // layer
+            chunk.i32(1);
+            match translation {
+                Some(t) => chunk.dict(&[("_t", &format!("{} {} {}", t.x, t.y, t.z))]),
+                None => chunk.dict(&[]),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> SparseVoxelOctree {
+        let path = format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"));
+        load(path).unwrap()
+    }
+
+    fn albedo(svo: &SparseVoxelOctree, x: u32, y: u32, z: u32) -> Option<[f32; 3]> {
+        svo.get_material(x, y, z).map(|material| material.albedo)
+    }
+
+    #[test]
+    fn loads_a_single_model_with_its_palette() {
+        let svo = fixture("single.vox");
+
+        assert_eq!(svo.depth(), 2);
+        assert_eq!(albedo(&svo, 0, 0, 2), Some([1.0, 0.0, 0.0]));
+        assert_eq!(albedo(&svo, 1, 3, 0), Some([0.0, 0.0, 1.0]));
+        assert_eq!(albedo(&svo, 1, 2, 2), Some([1.0, 0.0, 0.0]));
+        assert_eq!(svo.pack().voxels.len(), 3);
+    }
+
+    #[test]
+    fn falls_back_to_the_default_palette() {
+        let svo = fixture("default_palette.vox");
+
+        assert_eq!(albedo(&svo, 0, 0, 0), Some([1.0, 1.0, 1.0]));
+        assert_eq!(albedo(&svo, 1, 0, 0), Some([0xdd as f32 / 255.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn places_models_through_the_scene_graph() {
+        let svo = fixture("scene.vox");
+
+        assert_eq!(svo.depth(), 5);
+        assert_eq!(albedo(&svo, 0, 0, 2), Some([1.0, 1.0, 1.0]));
+        assert_eq!(albedo(&svo, 1, 0, 2), Some([1.0, 1.0, 1.0]));
+        assert_eq!(albedo(&svo, 21, 5, 3), Some([0.0, 1.0, 0.0]));
+        assert_eq!(albedo(&svo, 21, 5, 0), Some([1.0, 128.0 / 255.0, 0.0]));
+        assert_eq!(svo.pack().voxels.len(), 4);
+    }
+
+    #[test]
+    fn maps_matl_chunks_to_materials() {
+        let svo = fixture("scene.vox");
+
+        let metal = svo.get_material(21, 5, 3).unwrap();
+        assert_eq!((metal.roughness, metal.emission), (0.25, 0.0));
+
+        let emissive = svo.get_material(21, 5, 0).unwrap();
+        assert_eq!((emissive.roughness, emissive.emission), (1.0, 2.5));
+
+        assert_eq!(svo.get_material(0, 0, 2).unwrap().roughness, 1.0);
+        assert_eq!(svo.get(21, 5, 3).unwrap().material, 2);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(matches!(parse(b"RIFF"), Err(VoxError::InvalidHeader)));
+
+        let bytes = fs::read(format!(
+            "{}/tests/fixtures/single.vox",
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .unwrap();
+        assert!(matches!(
+            parse(&bytes[..bytes.len() - 10]),
+            Err(VoxError::Truncated)
+        ));
+    }
+
+    /// Asserts that both trees have the same depth, voxels and voxel materials.
+    fn assert_same_scene(a: &SparseVoxelOctree, b: &SparseVoxelOctree) {
+        assert_eq!(a.depth(), b.depth());
+        assert!(a.root() == b.root());
+        for (p, _, _) in a.iter() {
+            assert_eq!(a.get_material(p.x, p.y, p.z), b.get_material(p.x, p.y, p.z));
+        }
+    }
+
+    #[test]
+    fn round_trips_through_encode() {
+        for name in ["single.vox", "default_palette.vox", "scene.vox"] {
+            let svo = fixture(name);
+
+            assert_same_scene(&svo, &parse(&encode(&svo)).unwrap());
+        }
+    }
+
+    #[test]
+    fn round_trips_move_scenes_to_the_origin() {
+        let mut svo = SparseVoxelOctree::empty(3);
+        let red = svo.palette_mut().add(Material {
+            albedo: [1.0, 0.0, 0.0],
+            roughness: 1.0,
+            emission: 0.0,
+        });
+        let voxel = Node::Leaf(Some(Voxel { material: red }));
+        for (x, y, z) in [(5, 5, 5), (7, 5, 6), (5, 7, 7)] {
+            svo.insert(x, y, z, voxel.clone(), 3);
+        }
+
+        let copy = parse(&encode(&svo)).unwrap();
+
+        assert_eq!(copy.depth(), 2);
+        assert_eq!(copy.iter().count(), 3);
+        for (p, _, _) in svo.iter() {
+            let moved = p - UVec3::splat(5);
+            assert_eq!(
+                copy.get_material(moved.x, moved.y, moved.z),
+                svo.get_material(p.x, p.y, p.z)
+            );
+        }
+    }
+
+    #[test]
+    fn splits_wide_worlds_into_models() {
+        let mut svo = SparseVoxelOctree::empty(9);
+        let palette = svo.palette_mut();
+        palette.add(Material::zeroed());
+        let red = palette.add(Material {
+            albedo: [1.0, 0.0, 0.0],
+            roughness: 0.5,
+            emission: 0.0,
+        });
+        let glow = palette.add(Material {
+            albedo: [0.2, 0.4, 0.6],
+            roughness: 1.0,
+            emission: 3.0,
+        });
+        let voxel = |material| Node::Leaf(Some(Voxel { material }));
+        svo.insert(0, 0, 0, voxel(red), 9);
+        svo.insert(300, 7, 0, voxel(glow), 9);
+        svo.insert(3, 260, 400, voxel(red), 9);
+        svo.insert(256, 256, 256, voxel(glow), 5);
+
+        let bytes = encode(&svo);
+
+        let models = bytes.windows(4).filter(|id| id == b"XYZI").count();
+        assert_eq!(models, 4);
+        assert_same_scene(&svo, &parse(&bytes).unwrap());
+    }
+
+    #[test]
+    fn moves_materials_into_free_slots_when_they_fit() {
+        // Terrain-like indices starting at 0, with two materials of one color.
+        let mut svo = SparseVoxelOctree::empty(2);
+        let materials = [
+            Material {
+                albedo: [1.0, 0.0, 0.0],
+                roughness: 1.0,
+                emission: 0.0,
+            },
+            Material {
+                albedo: [1.0, 0.0, 0.0],
+                roughness: 0.5,
+                emission: 0.0,
+            },
+            Material {
+                albedo: [0.0, 1.0, 0.0],
+                roughness: 1.0,
+                emission: 2.0,
+            },
+        ];
+        for (x, material) in materials.into_iter().enumerate() {
+            let index = svo.palette_mut().add(material);
+            svo.insert(
+                x as u32,
+                0,
+                0,
+                Node::Leaf(Some(Voxel { material: index })),
+                2,
+            );
+        }
+
+        let copy = parse(&encode(&svo)).unwrap();
+
+        for (x, material) in materials.iter().enumerate() {
+            assert_eq!(copy.get_material(x as u32, 0, 0), Some(material));
+        }
+    }
+
+    #[test]
+    fn quantizes_palettes_that_do_not_fit() {
+        // The gradient cube has a material per voxel, 512 in all.
+        let svo = SparseVoxelOctree::new(3);
+
+        let copy = parse(&encode(&svo)).unwrap();
+
+        assert!(copy.root() != svo.root());
+        let slots = copy.pack().voxels.len();
+        assert!((2..=255).contains(&slots), "{slots}");
+        for (p, _, _) in svo.iter() {
+            let original = svo.get_material(p.x, p.y, p.z).unwrap().albedo;
+            let quantized = copy.get_material(p.x, p.y, p.z).unwrap().albedo;
+            for (a, b) in original.iter().zip(quantized) {
+                assert!((a - b).abs() < 0.1, "{original:?} {quantized:?}");
+            }
+        }
+    }
+}